@@ -0,0 +1,377 @@
+//! Command line tool to inspect, decode and validate MPEG audio files
+
+use simplemad::wav::{
+    decode_to_raw, decode_to_raw_dithered, decode_to_wav, decode_to_wav_dithered, SampleFormat,
+};
+use simplemad::{probe, Decoder, SimplemadError};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Seek, Write};
+use std::process;
+
+/// Exit code for a file that decoded without problems
+const EXIT_OK: i32 = 0;
+/// Exit code for a file with decoding errors
+const EXIT_INVALID: i32 = 1;
+/// Exit code for bad arguments or unreadable files
+const EXIT_FAILURE: i32 = 2;
+
+const USAGE: &str = "\
+Usage: simplemad <command> [options] <file>
+
+Commands:
+  info       Print headers, tags and duration
+  decode     Decode to WAV or raw PCM
+  frames     Print a table of frames
+  validate   Check for corruption
+
+Options for decode:
+  -f, --format <s16|s24|s32|f32>   Sample format (default s16)
+  -o, --output <path>              Output file (default stdout)
+      --raw                        Write raw interleaved PCM instead of WAV
+      --dither                     Dither when quantizing to 16 or 24 bits
+
+Options for frames and validate:
+      --headers                    Only decode frame headers
+
+validate exits with 0 for a clean file, 1 if errors were found and 2 if
+the file could not be read.";
+
+/// A parsed command line
+#[derive(Debug, PartialEq)]
+enum Command {
+    Info {
+        path: String,
+    },
+    Decode {
+        path: String,
+        output: Option<String>,
+        format: SampleFormat,
+        raw: bool,
+        dither: bool,
+    },
+    Frames {
+        path: String,
+        headers_only: bool,
+    },
+    Validate {
+        path: String,
+        headers_only: bool,
+    },
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let command = args.next().ok_or("missing command")?;
+    let mut path = None;
+    let mut output = None;
+    let mut format = SampleFormat::Int16;
+    let mut raw = false;
+    let mut dither = false;
+    let mut headers_only = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                let name = args.next().ok_or("missing sample format")?;
+                format = parse_format(&name)?;
+            }
+            "-o" | "--output" => output = Some(args.next().ok_or("missing output path")?),
+            "--raw" => raw = true,
+            "--dither" => dither = true,
+            "--headers" => headers_only = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    let path = path.ok_or("missing input file")?;
+    match command.as_str() {
+        "info" => Ok(Command::Info { path }),
+        "decode" => Ok(Command::Decode {
+            path,
+            output,
+            format,
+            raw,
+            dither,
+        }),
+        "frames" => Ok(Command::Frames { path, headers_only }),
+        "validate" => Ok(Command::Validate { path, headers_only }),
+        _ => Err(format!("unknown command '{}'", command)),
+    }
+}
+
+fn parse_format(name: &str) -> Result<SampleFormat, String> {
+    match name {
+        "s16" => Ok(SampleFormat::Int16),
+        "s24" => Ok(SampleFormat::Int24),
+        "s32" => Ok(SampleFormat::Int32),
+        "f32" => Ok(SampleFormat::Float32),
+        _ => Err(format!("unknown sample format '{}'", name)),
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, SimplemadError> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+fn info(path: &str) -> Result<i32, SimplemadError> {
+    let info = probe(open(path)?)?;
+    let bit_rate = &info.bit_rate;
+
+    println!("File:         {}", path);
+    println!("Format:       {:?} {:?}", info.version, info.layer);
+    println!("Mode:         {:?}", info.mode);
+    println!("Sample rate:  {} Hz", info.sample_rate);
+    println!(
+        "Bit rate:     {:?}, {} bps average",
+        info.bit_rate_mode, bit_rate.average
+    );
+    if let (Some(min), Some(max)) = (bit_rate.min, bit_rate.max) {
+        println!("              {} to {} bps", min, max);
+    }
+    println!("Duration:     {:.3} s", info.duration.as_secs_f64());
+    println!(
+        "Frames:       {} (from {:?})",
+        info.frame_count, info.source
+    );
+    println!("Audio offset: {}", info.audio_offset);
+    println!("ID3v2 tag:    {} bytes", info.id3v2_size);
+    println!("APEv2 tag:    {} bytes", info.ape_size);
+    println!("ID3v1 tag:    {} bytes", info.id3v1_size);
+    println!("File size:    {} bytes", info.stream_size);
+    Ok(EXIT_OK)
+}
+
+fn decode(
+    path: &str,
+    output: Option<&str>,
+    format: SampleFormat,
+    raw: bool,
+    dither: bool,
+) -> Result<i32, SimplemadError> {
+    let reader = open(path)?;
+    match (output, raw) {
+        (_, true) => {
+            let mut writer: Box<dyn Write> = match output {
+                Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+                None => Box::new(BufWriter::new(io::stdout())),
+            };
+            if dither {
+                decode_to_raw_dithered(reader, &mut writer, format)?;
+            } else {
+                decode_to_raw(reader, &mut writer, format)?;
+            }
+        }
+        (Some(output), false) => {
+            // The RIFF sizes are patched in place at the end
+            let mut file = BufWriter::new(File::create(output)?);
+            write_wav(reader, &mut file, format, dither)?;
+            file.flush()?;
+        }
+        (None, false) => {
+            // Standard output cannot seek, so the WAV file is built in
+            // memory
+            let mut wav = Cursor::new(Vec::new());
+            write_wav(reader, &mut wav, format, dither)?;
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            out.write_all(wav.get_ref())?;
+            out.flush()?;
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+fn write_wav<R: io::Read, W: Write + Seek>(
+    reader: R,
+    writer: W,
+    format: SampleFormat,
+    dither: bool,
+) -> Result<u64, SimplemadError> {
+    if dither {
+        decode_to_wav_dithered(reader, writer, format)
+    } else {
+        decode_to_wav(reader, writer, format)
+    }
+}
+
+fn frames(path: &str, headers_only: bool) -> Result<i32, SimplemadError> {
+    let reader = open(path)?;
+    let mut decoder = if headers_only {
+        Decoder::decode_headers(reader)?
+    } else {
+        Decoder::decode(reader)?
+    }
+    .with_crc_check();
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    writeln!(
+        out,
+        "{:>7} {:>10} {:>10} {:>7} {:>12} {:>9} {:>4}",
+        "frame", "offset", "position", "bitrate", "mode", "rate", "crc"
+    )?;
+    let mut count = 0;
+    while let Some(result) = decoder.next() {
+        match result {
+            Ok(frame) => {
+                writeln!(
+                    out,
+                    "{:>7} {:>10} {:>10.3} {:>7} {:>12} {:>9} {:>4}",
+                    count,
+                    frame.offset,
+                    frame.position.as_secs_f64(),
+                    frame.bit_rate / 1000,
+                    format!("{:?}", frame.mode),
+                    frame.sample_rate,
+                    match frame.crc_ok {
+                        Some(true) => "ok",
+                        Some(false) => "bad",
+                        None => "-",
+                    }
+                )?;
+                count += 1;
+            }
+            Err(SimplemadError::Mad(error)) => writeln!(
+                out,
+                "{:>7} {:>10} error: {:?}",
+                "-",
+                decoder.frame_offset(),
+                error
+            )?,
+            Err(SimplemadError::EOF) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    out.flush()?;
+    Ok(EXIT_OK)
+}
+
+fn validate(path: &str, headers_only: bool) -> Result<i32, SimplemadError> {
+    let report = match if headers_only {
+        simplemad::validate(open(path)?)
+    } else {
+        simplemad::validate_full(open(path)?)
+    } {
+        Ok(report) => report,
+        Err(SimplemadError::EOF) => {
+            println!("{}: no MPEG audio found", path);
+            return Ok(EXIT_INVALID);
+        }
+        Err(e) => return Err(e),
+    };
+
+    for issue in &report.issues {
+        println!("{}: {}", path, issue);
+    }
+    if report.is_valid() {
+        println!("{}: OK, {} frames", path, report.frame_count);
+        Ok(EXIT_OK)
+    } else {
+        println!(
+            "{}: {} issues in {} frames",
+            path,
+            report.issues.len(),
+            report.frame_count
+        );
+        Ok(EXIT_INVALID)
+    }
+}
+
+fn run(command: Command) -> Result<i32, SimplemadError> {
+    match command {
+        Command::Info { path } => info(&path),
+        Command::Decode {
+            path,
+            output,
+            format,
+            raw,
+            dither,
+        } => decode(&path, output.as_deref(), format, raw, dither),
+        Command::Frames { path, headers_only } => frames(&path, headers_only),
+        Command::Validate { path, headers_only } => validate(&path, headers_only),
+    }
+}
+
+fn main() {
+    let command = match parse_args(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("simplemad: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+
+    match run(command) {
+        Ok(code) => process::exit(code),
+        // The reader of a pipe, such as `head`, has seen all it wants
+        Err(SimplemadError::Read(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => {
+            process::exit(EXIT_OK)
+        }
+        Err(e) => {
+            eprintln!("simplemad: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_decode_options() {
+        let command = parse(&["decode", "--raw", "-f", "f32", "in.mp3", "-o", "out.pcm"]);
+        assert_eq!(
+            command,
+            Ok(Command::Decode {
+                path: "in.mp3".to_string(),
+                output: Some("out.pcm".to_string()),
+                format: SampleFormat::Float32,
+                raw: true,
+                dither: false,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["info"]).is_err());
+        assert!(parse(&["play", "in.mp3"]).is_err());
+        assert!(parse(&["decode", "-f", "s8", "in.mp3"]).is_err());
+        assert!(parse(&["info", "--verbose", "in.mp3"]).is_err());
+        assert!(parse(&["info", "a.mp3", "b.mp3"]).is_err());
+    }
+
+    #[test]
+    fn decodes_to_file() {
+        let path = "../simplemad/sample_mp3s/constant_stereo_128.mp3";
+        let output = env::temp_dir().join(format!("simplemad-cli-{}.wav", process::id()));
+        let output_str = output.to_str().unwrap();
+
+        let mut expected = Cursor::new(Vec::new());
+        decode_to_wav(open(path).unwrap(), &mut expected, SampleFormat::Int16).unwrap();
+        let expected = expected.into_inner();
+
+        decode(path, Some(output_str), SampleFormat::Int16, false, false).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), expected);
+
+        // Raw output is the data chunk, trimmed the same way
+        decode(path, Some(output_str), SampleFormat::Int16, true, false).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), &expected[44..]);
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn validates_sample() {
+        let path = "../simplemad/sample_mp3s/constant_stereo_128.mp3";
+        assert_eq!(validate(path, false).unwrap(), EXIT_OK);
+    }
+}
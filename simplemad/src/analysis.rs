@@ -0,0 +1,438 @@
+//! Loudness measurement after ITU-R BS.1770 and EBU R128, and ReplayGain
+//! 2.0 gains derived from it
+
+use crate::{Decoder, Frame, SimplemadError, FIXED_ONE};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::io::Read;
+
+/// Loudness that ReplayGain 2.0 normalizes to, in LUFS
+pub const REPLAY_GAIN_REFERENCE: f64 = -18.0;
+
+/// Gating blocks are built from steps of 100 ms
+const STEPS_PER_SECOND: u32 = 10;
+/// Steps in a 400 ms momentary block
+const MOMENTARY_STEPS: usize = 4;
+/// Steps in a 3 s short-term block
+const SHORT_TERM_STEPS: usize = 30;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+/// Taps of each phase of the true peak interpolation filter
+const TAPS_PER_PHASE: usize = 12;
+const OVERSAMPLING: usize = 4;
+
+/// The loudness of a stream
+#[derive(Clone, Debug)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, or negative infinity for silence
+    pub integrated: f64,
+    /// Loudness range in LU
+    pub range: f64,
+    /// Largest absolute sample value, where 1.0 is full scale
+    pub sample_peak: f64,
+    /// Largest absolute value of the signal oversampled four times
+    pub true_peak: f64,
+    /// Mean square energy of every momentary block, kept for album gain
+    blocks: Vec<f64>,
+}
+
+impl Loudness {
+    /// ReplayGain 2.0 track gain in dB
+    pub fn track_gain(&self) -> f64 {
+        REPLAY_GAIN_REFERENCE - self.integrated
+    }
+}
+
+/// The combined loudness of several streams
+#[derive(Clone, Copy, Debug)]
+pub struct AlbumGain {
+    /// ReplayGain 2.0 album gain in dB
+    pub gain: f64,
+    /// Integrated loudness of all streams together in LUFS
+    pub integrated: f64,
+    /// Largest sample peak of any stream
+    pub sample_peak: f64,
+    /// Largest true peak of any stream
+    pub true_peak: f64,
+}
+
+/// A second order IIR filter section
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two stages of the K-weighting filter for a sample rate, with
+/// coefficients derived as in libebur128 so that any rate can be used
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(sample_rate);
+
+    // High shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    // High pass removing frequencies below about 38 Hz
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Coefficients of the interpolation filter, a Hann windowed sinc with its
+/// cutoff at the original Nyquist frequency, grouped by output phase
+fn interpolation_filter() -> [[f64; TAPS_PER_PHASE]; OVERSAMPLING] {
+    let len = TAPS_PER_PHASE * OVERSAMPLING;
+    let center = (len - 1) as f64 / 2.0;
+    let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+
+    for n in 0..len {
+        let t = (n as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / len as f64).cos();
+        phases[n % OVERSAMPLING][n / OVERSAMPLING] = sinc * window;
+    }
+    phases
+}
+
+/// Per channel filter state
+struct Channel {
+    k_weighting: [Biquad; 2],
+    /// The most recent samples, newest first, for interpolation
+    history: [f64; TAPS_PER_PHASE],
+}
+
+/// Accumulates loudness statistics from decoded frames
+///
+/// `analyze` covers the common case of measuring a whole `Decoder`; a
+/// meter is useful when the frames are also needed for something else.
+pub struct LoudnessMeter {
+    sample_rate: u32,
+    channels: Vec<Channel>,
+    interpolation: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    step_len: usize,
+    step_pos: usize,
+    step_energy: f64,
+    /// Energy of the most recent steps, newest last
+    steps: VecDeque<f64>,
+    momentary: Vec<f64>,
+    short_term: Vec<f64>,
+    sample_peak: f64,
+    true_peak: f64,
+}
+
+impl Default for LoudnessMeter {
+    fn default() -> LoudnessMeter {
+        LoudnessMeter::new()
+    }
+}
+
+impl LoudnessMeter {
+    /// A meter that has measured nothing yet
+    pub fn new() -> LoudnessMeter {
+        LoudnessMeter {
+            sample_rate: 0,
+            channels: Vec::new(),
+            interpolation: interpolation_filter(),
+            step_len: 0,
+            step_pos: 0,
+            step_energy: 0.0,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS),
+            momentary: Vec::new(),
+            short_term: Vec::new(),
+            sample_peak: 0.0,
+            true_peak: 0.0,
+        }
+    }
+
+    /// Add the samples of a frame
+    ///
+    /// Filters are reset when the sample rate or channel count changes,
+    /// and gating blocks carry on across the change.
+    pub fn add_frame(&mut self, frame: &Frame) {
+        let len = frame.samples.first().map_or(0, |ch| ch.len());
+        if len == 0 {
+            return;
+        }
+        if frame.sample_rate != self.sample_rate || frame.samples.len() != self.channels.len() {
+            self.reset_filters(frame.sample_rate, frame.samples.len());
+        }
+
+        for idx in 0..len {
+            for (channel, samples) in self.channels.iter_mut().zip(&frame.samples) {
+                // The fixed-point value is used unclipped, so that peaks above
+                // full scale are reported
+                let x = f64::from(samples[idx].to_raw()) / FIXED_ONE;
+                self.sample_peak = self.sample_peak.max(x.abs());
+
+                channel.history.copy_within(..TAPS_PER_PHASE - 1, 1);
+                channel.history[0] = x;
+                for phase in &self.interpolation {
+                    let y: f64 = phase.iter().zip(&channel.history).map(|(h, x)| h * x).sum();
+                    self.true_peak = self.true_peak.max(y.abs());
+                }
+
+                let y = channel
+                    .k_weighting
+                    .iter_mut()
+                    .fold(x, |x, stage| stage.process(x));
+                self.step_energy += y * y;
+            }
+
+            self.step_pos += 1;
+            if self.step_pos == self.step_len {
+                self.end_step();
+            }
+        }
+    }
+
+    fn reset_filters(&mut self, sample_rate: u32, channel_count: usize) {
+        self.sample_rate = sample_rate;
+        self.step_len = (sample_rate / STEPS_PER_SECOND).max(1) as usize;
+        self.step_pos = 0;
+        self.step_energy = 0.0;
+        self.channels = (0..channel_count)
+            .map(|_| Channel {
+                k_weighting: k_weighting(sample_rate),
+                history: [0.0; TAPS_PER_PHASE],
+            })
+            .collect();
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps
+            .push_back(self.step_energy / self.step_len as f64);
+        self.step_pos = 0;
+        self.step_energy = 0.0;
+
+        if self.steps.len() >= MOMENTARY_STEPS {
+            let recent = self.steps.iter().rev().take(MOMENTARY_STEPS);
+            self.momentary
+                .push(recent.sum::<f64>() / MOMENTARY_STEPS as f64);
+        }
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.short_term
+                .push(self.steps.iter().sum::<f64>() / SHORT_TERM_STEPS as f64);
+        }
+    }
+
+    /// The loudness of everything added so far
+    pub fn finish(self) -> Loudness {
+        Loudness {
+            integrated: gated_loudness(&self.momentary),
+            range: loudness_range(&self.short_term),
+            sample_peak: self.sample_peak,
+            true_peak: self.true_peak.max(self.sample_peak),
+            blocks: self.momentary,
+        }
+    }
+}
+
+/// Measure the loudness of every frame of a decoder
+///
+/// Frames that fail to decode are skipped.
+pub fn analyze<R: Read>(decoder: Decoder<R>) -> Result<Loudness, SimplemadError> {
+    let mut meter = LoudnessMeter::new();
+
+    for result in decoder {
+        match result {
+            Ok(frame) => meter.add_frame(&frame),
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(SimplemadError::EOF) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(meter.finish())
+}
+
+/// The ReplayGain 2.0 album gain of several streams, gated as if they
+/// were played one after another
+pub fn album_gain(tracks: &[Loudness]) -> AlbumGain {
+    let blocks: Vec<f64> = tracks
+        .iter()
+        .flat_map(|t| t.blocks.iter().copied())
+        .collect();
+    let integrated = gated_loudness(&blocks);
+
+    AlbumGain {
+        gain: REPLAY_GAIN_REFERENCE - integrated,
+        integrated,
+        sample_peak: tracks.iter().map(|t| t.sample_peak).fold(0.0, f64::max),
+        true_peak: tracks.iter().map(|t| t.true_peak).fold(0.0, f64::max),
+    }
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / f64::from(count))
+    }
+}
+
+/// Integrated loudness of momentary blocks, with the absolute and relative
+/// gates of BS.1770
+fn gated_loudness(blocks: &[f64]) -> f64 {
+    let absolute = energy(ABSOLUTE_GATE);
+    let relative = match mean(blocks.iter().copied().filter(|&e| e > absolute)) {
+        Some(mean) => mean * energy(RELATIVE_GATE) / energy(0.0),
+        None => return f64::NEG_INFINITY,
+    };
+
+    let gate = absolute.max(relative);
+    mean(blocks.iter().copied().filter(|&e| e > gate)).map_or(f64::NEG_INFINITY, loudness)
+}
+
+/// Loudness range of short-term blocks as defined in EBU Tech 3342, the
+/// spread between the 10th and 95th percentiles of the gated blocks
+fn loudness_range(blocks: &[f64]) -> f64 {
+    let absolute = energy(ABSOLUTE_GATE);
+    let relative = match mean(blocks.iter().copied().filter(|&e| e > absolute)) {
+        Some(mean) => mean * energy(RANGE_RELATIVE_GATE) / energy(0.0),
+        None => return 0.0,
+    };
+
+    let gate = absolute.max(relative);
+    let mut gated: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&e| e > gate)
+        .map(loudness)
+        .collect();
+    if gated.is_empty() {
+        return 0.0;
+    }
+    gated.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::path::Path;
+
+    /// Frames of a sine wave at the given peak amplitude, starting an
+    /// eighth of a cycle in
+    fn sine_frames(frequency: f64, amplitude: f64, seconds: usize, channels: usize) -> Vec<Frame> {
+        crate::test::sine_frames(48000, 1200, channels, seconds * 40, |t| {
+            amplitude * (2.0 * PI * frequency * t + PI / 4.0).sin()
+        })
+    }
+
+    fn measure(frames: &[Frame]) -> Loudness {
+        let mut meter = LoudnessMeter::new();
+        for frame in frames {
+            meter.add_frame(frame);
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn reference_tone() {
+        // A 997 Hz sine at -23 dBFS in both channels measures -23 LUFS,
+        // as in the EBU Tech 3341 test cases
+        let amplitude = 10f64.powf(-23.0 / 20.0);
+        let loudness = measure(&sine_frames(997.0, amplitude, 10, 2));
+
+        assert!(
+            (loudness.integrated + 23.0).abs() < 0.1,
+            "{}",
+            loudness.integrated
+        );
+        assert!(loudness.range < 0.1);
+        assert!((loudness.track_gain() - 5.0).abs() < 0.1);
+        assert!((loudness.sample_peak - amplitude).abs() < 1e-3);
+        assert!((loudness.true_peak - amplitude).abs() < 0.01 * amplitude);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // A quarter of the sample rate is sampled 45 degrees off its peaks
+        let frames = sine_frames(12000.0, 0.5, 2, 1);
+        let loudness = measure(&frames);
+
+        assert!(loudness.sample_peak < 0.5);
+        assert!(loudness.true_peak > loudness.sample_peak * 1.3);
+        assert!((loudness.true_peak - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn silence_and_album() {
+        let silent = measure(&sine_frames(997.0, 0.0, 5, 2));
+        assert_eq!(silent.integrated, f64::NEG_INFINITY);
+        assert_eq!(silent.sample_peak, 0.0);
+
+        let loud = measure(&sine_frames(997.0, 0.1, 10, 2));
+        let quiet = measure(&sine_frames(997.0, 0.01, 10, 2));
+        let album = album_gain(&[loud.clone(), quiet, silent]);
+
+        // The quiet track falls below the relative gate
+        assert!((album.integrated - loud.integrated).abs() < 0.01);
+        assert_eq!(album.sample_peak, loud.sample_peak);
+    }
+
+    #[test]
+    fn decoded_stream() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let file = File::open(path).unwrap();
+        let loudness = analyze(Decoder::decode(file).unwrap()).unwrap();
+
+        assert!(loudness.integrated > -40.0 && loudness.integrated < 0.0);
+        assert!(loudness.true_peak >= loudness.sample_peak);
+        assert!(loudness.sample_peak > 0.0);
+    }
+}
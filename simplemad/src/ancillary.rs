@@ -0,0 +1,115 @@
+//! The ancillary data that follows the audio data of a frame
+
+use simplemad_sys::*;
+
+/// The ancillary bits of a frame, which broadcasters use for text, surround
+/// side information or timecodes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AncillaryData {
+    /// The bits packed from the most significant bit of the first byte,
+    /// with the unused low bits of the last byte zeroed
+    pub bytes: Vec<u8>,
+    /// Number of ancillary bits, which need not be a multiple of 8
+    pub bit_len: usize,
+}
+
+impl AncillaryData {
+    /// Copy `bit_len` bits starting at `ptr`
+    ///
+    /// # Safety
+    ///
+    /// The bits must lie in memory that libmad has not freed or reused.
+    pub(crate) unsafe fn read(ptr: &MadBitPtr, bit_len: usize) -> AncillaryData {
+        if bit_len == 0 || ptr.byte.is_null() {
+            return AncillaryData::default();
+        }
+        // Bits of the first byte already read, which are not ancillary
+        let skip = 8 - usize::from(ptr.left).clamp(1, 8);
+        let source =
+            std::slice::from_raw_parts(ptr.byte.cast::<u8>(), (skip + bit_len).div_ceil(8));
+        AncillaryData::from_bits(source, skip, bit_len)
+    }
+
+    /// Copy `bit_len` bits from `source`, starting `skip` bits into it
+    fn from_bits(source: &[u8], skip: usize, bit_len: usize) -> AncillaryData {
+        let mut bytes: Vec<u8> = (0..bit_len.div_ceil(8))
+            .map(|i| {
+                let next = source.get(i + 1).copied().unwrap_or(0);
+                match skip {
+                    0 => source[i],
+                    _ => (source[i] << skip) | (next >> (8 - skip)),
+                }
+            })
+            .collect();
+        if let Some(last) = bytes.last_mut() {
+            *last &= 0xff << ((8 - bit_len % 8) % 8);
+        }
+        AncillaryData { bytes, bit_len }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raw::{FrameDecoder, Stream};
+    use crate::{Decoder, BUFFER_GUARD};
+
+    #[test]
+    fn packs_bits() {
+        let source = [0b1010_1100, 0b0101_1111, 0b1100_0000];
+        let aligned = AncillaryData::from_bits(&source, 0, 12);
+        assert_eq!(aligned.bytes, vec![0b1010_1100, 0b0101_0000]);
+        let shifted = AncillaryData::from_bits(&source, 3, 13);
+        assert_eq!(shifted.bytes, vec![0b0110_0010, 0b1111_1000]);
+        assert_eq!(shifted.bit_len, 13);
+        assert_eq!(AncillaryData::from_bits(&source, 5, 0).bytes.len(), 0);
+    }
+
+    #[test]
+    fn reads_bit_pointer() {
+        let source = [0b1010_1100u8, 0b0101_1111];
+        let ptr = MadBitPtr {
+            byte: source.as_ptr().cast_mut().cast(),
+            cache: u16::from(source[0]),
+            left: 5,
+        };
+        let data = unsafe { AncillaryData::read(&ptr, 7) };
+        assert_eq!(data.bytes, vec![0b0110_0010]);
+    }
+
+    #[test]
+    fn decoder_reports_ancillary_data() {
+        let path = "sample_mp3s/constant_stereo_128.mp3";
+        let mut data = std::fs::read(path).unwrap();
+        data.extend_from_slice(&[0; BUFFER_GUARD]);
+
+        // The same bits as reading the stream directly
+        let mut stream = Stream::new();
+        let mut frame = FrameDecoder::new();
+        stream.set_buffer(&data);
+        let mut expected = Vec::new();
+        loop {
+            match frame.decode(&mut stream) {
+                Ok(_) => expected.push(stream.ancillary_data()),
+                Err(crate::SimplemadError::Mad(MadError::BufLen)) => break,
+                Err(_) => continue,
+            }
+        }
+
+        let frames: Vec<AncillaryData> = Decoder::decode(&data[..])
+            .unwrap()
+            .with_ancillary_data()
+            .filter_map(|r| r.ok())
+            .map(|f| f.ancillary.unwrap())
+            .collect();
+        assert_eq!(frames, expected);
+        assert!(frames.iter().any(|a| a.bit_len > 0));
+        for ancillary in &frames {
+            assert_eq!(ancillary.bytes.len(), ancillary.bit_len.div_ceil(8));
+        }
+
+        let plain = Decoder::decode(std::fs::File::open(path).unwrap()).unwrap();
+        assert!(plain.filter_map(|r| r.ok()).all(|f| f.ancillary.is_none()));
+    }
+}
@@ -0,0 +1,378 @@
+//! A safe front end to libmad's callback-driven `mad_decoder_run`, for
+//! code modelled on `minimad.c`
+
+use crate::SimplemadError;
+use simplemad_sys::*;
+use std::any::Any;
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+
+type InputFn<'a> = dyn FnMut(&mut Vec<u8>) -> MadFlow + 'a;
+type HeaderFn<'a> = dyn FnMut(&MadHeader) -> MadFlow + 'a;
+type FilterFn<'a> = dyn FnMut(&MadHeader, &mut [[[i32; 32]; 36]; 2]) -> MadFlow + 'a;
+type OutputFn<'a> = dyn FnMut(&MadHeader, &MadPcm) -> MadFlow + 'a;
+type ErrorFn<'a> = dyn FnMut(SimplemadError, u64) -> MadFlow + 'a;
+
+/// Decodes a stream by calling closures at each stage, as libmad's own
+/// high-level decoder does
+///
+/// Every closure returns a `MadFlow`: `Continue` to carry on, `Ignore` to
+/// skip the rest of the current frame, `Stop` to finish decoding and
+/// `Break` to abandon it with `SimplemadError::Break`. A panic in a closure
+/// stops decoding and is resumed by `run`.
+///
+/// ```no_run
+/// use simplemad::CallbackDecoder;
+/// use simplemad_sys::MadFlow;
+/// use std::fs;
+///
+/// let mut data = Some(fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap());
+/// CallbackDecoder::new(|buffer| match data.take() {
+///     Some(data) => {
+///         buffer.extend_from_slice(&data);
+///         MadFlow::Continue
+///     }
+///     None => MadFlow::Stop,
+/// })
+/// .on_output(|_, pcm| {
+///     println!("{} samples", pcm.length);
+///     MadFlow::Continue
+/// })
+/// .run()
+/// .unwrap();
+/// ```
+pub struct CallbackDecoder<'a> {
+    callbacks: Callbacks<'a>,
+    options: i32,
+}
+
+struct Callbacks<'a> {
+    input: Box<InputFn<'a>>,
+    header: Option<Box<HeaderFn<'a>>>,
+    filter: Option<Box<FilterFn<'a>>>,
+    output: Option<Box<OutputFn<'a>>>,
+    error: Option<Box<ErrorFn<'a>>>,
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+    broken: bool,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<'a> CallbackDecoder<'a> {
+    /// Create a decoder that reads its input through `input`
+    ///
+    /// `input` is called whenever libmad needs more data, with a buffer
+    /// holding the bytes not yet consumed, and should append to it. Return
+    /// `MadFlow::Stop` at the end of the input. The last frame decodes only
+    /// when at least 8 zero bytes follow it.
+    pub fn new<F>(input: F) -> CallbackDecoder<'a>
+    where
+        F: FnMut(&mut Vec<u8>) -> MadFlow + 'a,
+    {
+        CallbackDecoder {
+            callbacks: Callbacks {
+                input: Box::new(input),
+                header: None,
+                filter: None,
+                output: None,
+                error: None,
+                buffer: Vec::new(),
+                buffer_offset: 0,
+                broken: false,
+                panic: None,
+            },
+            options: 0,
+        }
+    }
+
+    /// Call `header` with each frame header before the frame is decoded
+    pub fn on_header<F>(mut self, header: F) -> CallbackDecoder<'a>
+    where
+        F: FnMut(&MadHeader) -> MadFlow + 'a,
+    {
+        self.callbacks.header = Some(Box::new(header));
+        self
+    }
+
+    /// Call `filter` with the subband samples of each decoded frame, which
+    /// it may change, before synthesis
+    pub fn on_filter<F>(mut self, filter: F) -> CallbackDecoder<'a>
+    where
+        F: FnMut(&MadHeader, &mut [[[i32; 32]; 36]; 2]) -> MadFlow + 'a,
+    {
+        self.callbacks.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Call `output` with the PCM synthesized from each frame
+    pub fn on_output<F>(mut self, output: F) -> CallbackDecoder<'a>
+    where
+        F: FnMut(&MadHeader, &MadPcm) -> MadFlow + 'a,
+    {
+        self.callbacks.output = Some(Box::new(output));
+        self
+    }
+
+    /// Call `error` with each recoverable error and the byte offset in the
+    /// input of the frame that caused it
+    ///
+    /// Without an error closure, libmad skips bad frames and mutes the
+    /// second of two consecutive frames that fail their CRC check.
+    /// `MadFlow::Ignore` goes on to synthesize a frame that failed to
+    /// decode, which then holds the previous frame's samples.
+    pub fn on_error<F>(mut self, error: F) -> CallbackDecoder<'a>
+    where
+        F: FnMut(SimplemadError, u64) -> MadFlow + 'a,
+    {
+        self.callbacks.error = Some(Box::new(error));
+        self
+    }
+
+    /// Set `MAD_OPTION_*` flags for the stream
+    pub fn with_options(mut self, options: i32) -> CallbackDecoder<'a> {
+        self.options = options;
+        self
+    }
+
+    /// Decode until the input ends or a closure stops decoding
+    pub fn run(mut self) -> Result<(), SimplemadError> {
+        // libmad decodes each header on its own only if there is a header
+        // callback, and handles errors itself if there is no error callback
+        let callbacks = &self.callbacks;
+        let header: Option<MadHeaderFunc> = callbacks.header.as_ref().and(Some(header_func));
+        let filter: Option<MadFilterFunc> = callbacks.filter.as_ref().and(Some(filter_func));
+        let output: Option<MadOutputFunc> = callbacks.output.as_ref().and(Some(output_func));
+        let error: Option<MadErrorFunc> = callbacks.error.as_ref().and(Some(error_func));
+
+        let data: *mut Callbacks = &mut self.callbacks;
+        let mut decoder = MadDecoder::default();
+        let result = unsafe {
+            mad_decoder_init(
+                &mut decoder,
+                data.cast(),
+                Some(input_func),
+                header,
+                filter,
+                output,
+                error,
+                None,
+            );
+            decoder.options = self.options;
+            let result = mad_decoder_run(&mut decoder, MadDecoderMode::Sync);
+            mad_decoder_finish(&mut decoder);
+            result
+        };
+
+        if let Some(payload) = self.callbacks.panic.take() {
+            panic::resume_unwind(payload);
+        }
+        match result {
+            0 => Ok(()),
+            _ if self.callbacks.broken => Err(SimplemadError::Break),
+            // libmad stops without a callback only when it runs out of
+            // memory
+            _ => Err(SimplemadError::Mad(MadError::NoMem)),
+        }
+    }
+}
+
+/// Run a closure for a trampoline, turning a panic into `MadFlow::Break`
+/// and keeping it for `run` to resume
+unsafe fn guard<F>(data: *mut c_void, f: F) -> MadFlow
+where
+    F: FnOnce(&mut Callbacks) -> MadFlow,
+{
+    let callbacks = &mut *(data as *mut Callbacks);
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *callbacks))) {
+        Ok(MadFlow::Break) => {
+            callbacks.broken = true;
+            MadFlow::Break
+        }
+        Ok(flow) => flow,
+        Err(payload) => {
+            callbacks.panic = Some(payload);
+            MadFlow::Break
+        }
+    }
+}
+
+unsafe extern "C" fn input_func(data: *mut c_void, stream: *mut MadStream) -> MadFlow {
+    let stream = &mut *stream;
+    guard(data, |callbacks| {
+        // Keep the bytes from the frame libmad stopped at
+        if !stream.buffer.is_null() {
+            let consumed = stream.next_frame as usize - stream.buffer as usize;
+            let consumed = consumed.min(callbacks.buffer.len());
+            callbacks.buffer.drain(..consumed);
+            callbacks.buffer_offset += consumed as u64;
+        }
+        let flow = (callbacks.input)(&mut callbacks.buffer);
+        mad_stream_buffer(
+            stream,
+            callbacks.buffer.as_ptr(),
+            callbacks.buffer.len() as c_ulong,
+        );
+        flow
+    })
+}
+
+unsafe extern "C" fn header_func(data: *mut c_void, header: *const MadHeader) -> MadFlow {
+    let header = &*header;
+    guard(data, |callbacks| match callbacks.header {
+        Some(ref mut f) => f(header),
+        None => MadFlow::Continue,
+    })
+}
+
+unsafe extern "C" fn filter_func(
+    data: *mut c_void,
+    _stream: *const MadStream,
+    frame: *mut MadFrame,
+) -> MadFlow {
+    let frame = &mut *frame;
+    guard(data, |callbacks| match callbacks.filter {
+        Some(ref mut f) => f(&frame.header, &mut frame.sbsample),
+        None => MadFlow::Continue,
+    })
+}
+
+unsafe extern "C" fn output_func(
+    data: *mut c_void,
+    header: *const MadHeader,
+    pcm: *mut MadPcm,
+) -> MadFlow {
+    let (header, pcm) = (&*header, &*pcm);
+    guard(data, |callbacks| match callbacks.output {
+        Some(ref mut f) => f(header, pcm),
+        None => MadFlow::Continue,
+    })
+}
+
+unsafe extern "C" fn error_func(
+    data: *mut c_void,
+    stream: *mut MadStream,
+    _frame: *mut MadFrame,
+) -> MadFlow {
+    let stream = &*stream;
+    guard(data, |callbacks| {
+        let error = match stream.error() {
+            Ok(error) => SimplemadError::Mad(error),
+            Err(code) => SimplemadError::Unknown(code),
+        };
+        let offset =
+            callbacks.buffer_offset + (stream.this_frame as usize - stream.buffer as usize) as u64;
+        match callbacks.error {
+            Some(ref mut f) => f(error, offset),
+            None => MadFlow::Continue,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Decoder, BUFFER_GUARD};
+    use std::cell::Cell;
+
+    /// An input closure feeding `data` in chunks, then the guard
+    fn chunked(data: Vec<u8>, chunk: usize) -> impl FnMut(&mut Vec<u8>) -> MadFlow {
+        let mut pos = 0;
+        move |buffer| {
+            if pos > data.len() {
+                return MadFlow::Stop;
+            }
+            let end = (pos + chunk).min(data.len());
+            buffer.extend_from_slice(&data[pos..end]);
+            if end == data.len() {
+                buffer.extend_from_slice(&[0; BUFFER_GUARD]);
+                pos = end + 1;
+            } else {
+                pos = end;
+            }
+            MadFlow::Continue
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap()
+    }
+
+    #[test]
+    fn matches_decoder() {
+        let mut expected = Vec::new();
+        let mut data = sample();
+        data.extend_from_slice(&[0; BUFFER_GUARD]);
+        for frame in Decoder::decode(&data[..]).unwrap().flatten() {
+            expected.push(frame.samples[0][100].to_raw());
+        }
+
+        let mut headers = 0;
+        let mut samples = Vec::new();
+        CallbackDecoder::new(chunked(sample(), 1000))
+            .on_header(|header| {
+                assert_eq!(header.sample_rate, 44100);
+                headers += 1;
+                MadFlow::Continue
+            })
+            .on_output(|_, pcm| {
+                samples.push(pcm.samples[0][100]);
+                MadFlow::Continue
+            })
+            .run()
+            .unwrap();
+
+        assert_eq!(headers, 194);
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn filter_and_flow() {
+        let frames = Cell::new(0);
+        let result = CallbackDecoder::new(chunked(sample(), 4096))
+            .on_filter(|_, sbsample| {
+                // Silence everything
+                *sbsample = [[[0; 32]; 36]; 2];
+                MadFlow::Continue
+            })
+            .on_output(|_, pcm| {
+                assert!(pcm.samples[0].iter().all(|&s| s == 0));
+                frames.set(frames.get() + 1);
+                if frames.get() == 10 {
+                    MadFlow::Break
+                } else {
+                    MadFlow::Continue
+                }
+            })
+            .run();
+
+        assert!(matches!(result, Err(SimplemadError::Break)));
+        assert_eq!(frames.get(), 10);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut data = vec![0x55; 300];
+        data.extend_from_slice(&sample());
+        let mut offsets = Vec::new();
+        CallbackDecoder::new(chunked(data, 2048))
+            .on_error(|error, offset| {
+                offsets.push(offset);
+                assert!(matches!(error, SimplemadError::Mad(_)));
+                MadFlow::Continue
+            })
+            .run()
+            .unwrap();
+        // The junk, then the guard bytes after the last frame
+        assert_eq!(offsets.len(), 2);
+        assert_eq!(offsets[0], 0);
+        assert_eq!(offsets[1], 300 + 81083);
+    }
+
+    #[test]
+    #[should_panic(expected = "from a callback")]
+    fn resumes_panics() {
+        let _ = CallbackDecoder::new(chunked(sample(), 4096))
+            .on_header(|_| panic!("from a callback"))
+            .run();
+    }
+}
@@ -0,0 +1,277 @@
+//! CRC-16 checks of protected frames, computed from the raw frame bytes
+//! so that they also work for header-only scans
+
+use simplemad_sys::*;
+
+const CRC_POLY: u16 = 0x8005;
+
+/// Layer II subband limits and bit allocation classes, indexed as in
+/// ISO/IEC 11172-3 Tables B.2a-d and ISO/IEC 13818-3 Table B.1
+const SBQUANT_TABLE: [(usize, &[u8]); 5] = [
+    (
+        27,
+        &[
+            7, 7, 7, 6, 6, 6, 6, 6, 6, 6, 6, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0,
+        ],
+    ),
+    (
+        30,
+        &[
+            7, 7, 7, 6, 6, 6, 6, 6, 6, 6, 6, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0,
+            0,
+        ],
+    ),
+    (8, &[5, 5, 2, 2, 2, 2, 2, 2]),
+    (12, &[5, 5, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2]),
+    (
+        30,
+        &[
+            4, 4, 4, 4, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+            1,
+        ],
+    ),
+];
+
+/// Number of bit allocation bits for each allocation class
+const NBAL_TABLE: [u32; 8] = [2, 2, 3, 3, 4, 4, 4, 4];
+
+/// Reads big-endian bit fields from a byte slice
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.bytes.get(self.pos / 8)?;
+            value = (value << 1) | u32::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+fn crc_update(mut crc: u16, value: u32, bits: u32) -> u16 {
+    for bit in (0..bits).rev() {
+        let top = crc >> 15;
+        crc <<= 1;
+        if top ^ ((value >> bit) as u16 & 1) != 0 {
+            crc ^= CRC_POLY;
+        }
+    }
+    crc
+}
+
+fn channel_count(header: &MadHeader) -> usize {
+    if header.mode() == Ok(MadMode::SingleChannel) {
+        1
+    } else {
+        2
+    }
+}
+
+/// Number of bits after the CRC word that it protects, or `None` if they
+/// run past the end of the frame
+fn protected_bits(frame: &[u8], header: &MadHeader) -> Option<usize> {
+    let nch = channel_count(header);
+    let joint_bound = 4 + header.mode_extension as usize * 4;
+
+    match header.layer().ok()? {
+        MadLayer::LayerI => {
+            let bound = if header.mode() == Ok(MadMode::JointStereo) {
+                joint_bound
+            } else {
+                32
+            };
+            Some(4 * (bound * nch + (32 - bound)))
+        }
+        MadLayer::LayerII => {
+            let (sblimit, offsets) = SBQUANT_TABLE[layer_ii_table(header, nch)];
+            let bound = if header.mode() == Ok(MadMode::JointStereo) {
+                joint_bound.min(sblimit)
+            } else {
+                sblimit
+            };
+
+            let mut reader = BitReader {
+                bytes: &frame[6..],
+                pos: 0,
+            };
+            let mut allocation = [[0; 32]; 2];
+            for sb in 0..sblimit {
+                let nbal = NBAL_TABLE[offsets[sb] as usize];
+                if sb < bound {
+                    for channel in allocation.iter_mut().take(nch) {
+                        channel[sb] = reader.read(nbal)?;
+                    }
+                } else {
+                    let value = reader.read(nbal)?;
+                    allocation[0][sb] = value;
+                    allocation[1][sb] = value;
+                }
+            }
+
+            // Two bits of scalefactor selection for every allocated subband
+            let scfsi = (0..sblimit)
+                .flat_map(|sb| allocation.iter().take(nch).map(move |ch| ch[sb]))
+                .filter(|&a| a != 0)
+                .count();
+            Some(reader.pos + 2 * scfsi)
+        }
+        MadLayer::LayerIII => {
+            let side_info_len = match (header.flags & MAD_FLAG_LSF_EXT != 0, nch) {
+                (false, 1) => 17,
+                (false, _) => 32,
+                (true, 1) => 9,
+                (true, _) => 17,
+            };
+            Some(side_info_len * 8)
+        }
+    }
+}
+
+/// Index into `SBQUANT_TABLE` for a Layer II frame, as chosen by libmad
+fn layer_ii_table(header: &MadHeader, nch: usize) -> usize {
+    if header.flags & MAD_FLAG_LSF_EXT != 0 {
+        return 4;
+    }
+
+    let free_format = header.flags & MAD_FLAG_FREEFORMAT != 0;
+    match (free_format, header.bit_rate / nch as c_ulong) {
+        (false, 0..=48_000) if header.sample_rate == 32000 => 3,
+        (false, 0..=48_000) => 2,
+        (false, 0..=80_000) => 0,
+        _ if header.sample_rate == 48000 => 0,
+        _ => 1,
+    }
+}
+
+/// The CRC-16 of a protected frame, computed over the last two bytes of
+/// the header and the bits that follow the CRC word, or `None` if the frame
+/// is not protected or is too short
+pub(crate) fn compute_crc(frame: &[u8], header: &MadHeader) -> Option<u16> {
+    if header.flags & MAD_FLAG_PROTECTION == 0 || frame.len() < 6 {
+        return None;
+    }
+
+    let bits = protected_bits(frame, header)?;
+    if 6 * 8 + bits > frame.len() * 8 {
+        return None;
+    }
+
+    let mut crc = crc_update(0xffff, u32::from(frame[2]) << 8 | u32::from(frame[3]), 16);
+    let mut reader = BitReader {
+        bytes: &frame[6..],
+        pos: 0,
+    };
+    let mut remaining = bits as u32;
+    while remaining > 0 {
+        let count = remaining.min(8);
+        crc = crc_update(crc, reader.read(count)?, count);
+        remaining -= count;
+    }
+    Some(crc)
+}
+
+/// Whether a protected frame matches its CRC word, or `None` if the frame
+/// is not protected
+pub(crate) fn check_crc(frame: &[u8], header: &MadHeader) -> Option<bool> {
+    if header.flags & MAD_FLAG_PROTECTION == 0 {
+        return None;
+    }
+
+    let target = frame
+        .get(4..6)
+        .map(|b| u16::from(b[0]) << 8 | u16::from(b[1]));
+    match (compute_crc(frame, header), target) {
+        (Some(crc), Some(target)) => Some(crc == target),
+        _ => Some(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Decoder, Frame, SimplemadError};
+
+    /// Build a stream of identical protected frames of silence, with
+    /// `payload` written after the CRC word and a correct CRC
+    fn protected_stream(header: [u8; 4], len: usize, payload: &[u8], count: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; len];
+        frame[..4].copy_from_slice(&header);
+        frame[6..6 + payload.len()].copy_from_slice(payload);
+
+        let crc = {
+            // libmad only syncs to a frame that is followed by another
+            let pair = frame.repeat(2);
+            let mut decoder = Decoder::decode_headers(&pair[..]).unwrap();
+            decoder.get_frame().unwrap();
+            compute_crc(&frame, decoder.header()).unwrap()
+        };
+        frame[4..6].copy_from_slice(&crc.to_be_bytes());
+
+        frame.repeat(count)
+    }
+
+    fn full_decode(bytes: &[u8]) -> Vec<Result<Frame, SimplemadError>> {
+        Decoder::decode(bytes).unwrap().collect()
+    }
+
+    fn header_scan(bytes: &[u8]) -> Vec<Option<bool>> {
+        Decoder::decode_headers(bytes)
+            .unwrap()
+            .with_crc_check()
+            .filter_map(|r| r.ok())
+            .map(|f| f.crc_ok)
+            .collect()
+    }
+
+    #[test]
+    fn matches_libmad() {
+        // MPEG-1 Layer I mono, Layer II joint stereo with some bits
+        // allocated, and Layer III mono, each at 44.1 kHz
+        let streams = [
+            protected_stream([0xff, 0xfe, 0x10, 0xc0], 32, &[], 8),
+            protected_stream([0xff, 0xfc, 0x80, 0x50], 417, &[0xff, 0xff], 8),
+            protected_stream([0xff, 0xfa, 0x90, 0xc0], 417, &[], 8),
+        ];
+
+        for bytes in &streams {
+            // libmad checks the CRC in a full decode
+            assert!(full_decode(bytes).iter().all(|r| r.is_ok()));
+            assert!(header_scan(bytes).iter().all(|&crc| crc == Some(true)));
+        }
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut bytes = protected_stream([0xff, 0xfc, 0x80, 0x50], 417, &[0xff, 0xff], 8);
+        bytes[417 * 2 + 7] ^= 0x01;
+
+        let results = full_decode(&bytes);
+        assert!(matches!(
+            results[2],
+            Err(SimplemadError::Mad(MadError::BadCRC))
+        ));
+
+        let crcs = header_scan(&bytes);
+        assert_eq!(crcs[2], Some(false));
+        assert_eq!(crcs.iter().filter(|&&crc| crc == Some(true)).count(), 6);
+
+        // With checking enabled the damaged frame is still decoded
+        let decoded: Vec<_> = Decoder::decode(&bytes[..])
+            .unwrap()
+            .with_crc_check()
+            .map(|r| r.unwrap().crc_ok)
+            .collect();
+        assert_eq!(decoded[2], Some(false));
+    }
+
+    #[test]
+    fn unprotected_frames() {
+        let bytes = std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap();
+        assert!(header_scan(&bytes).iter().all(|crc| crc.is_none()));
+    }
+}
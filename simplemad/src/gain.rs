@@ -0,0 +1,386 @@
+//! ReplayGain values stored in ID3v2 and LAME tags, and the fixed-point
+//! gain that `Decoder::with_gain` applies
+
+use crate::xing::parse_xing;
+use crate::{Decoder, MadFixed32, SimplemadError, FIXED_ONE};
+use std::io;
+
+/// The largest gain in dB that libmad's fixed-point format can hold, a
+/// factor of just under 8
+pub const MAX_GAIN: f64 = 18.06;
+
+/// The gain for `Decoder::with_gain` to apply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gain {
+    /// The track gain stored in the stream
+    Track,
+    /// The album gain stored in the stream, or the track gain if there is
+    /// no album gain
+    Album,
+    /// A fixed gain in dB, at most `MAX_GAIN`
+    Manual(f64),
+}
+
+/// ReplayGain values stored in a stream
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    /// Track gain in dB
+    pub track_gain: Option<f64>,
+    /// Peak sample amplitude of the track, where 1.0 is full scale
+    pub track_peak: Option<f64>,
+    /// Album gain in dB
+    pub album_gain: Option<f64>,
+    /// Peak sample amplitude of the album
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    /// Read the values from `TXXX:REPLAYGAIN_*` frames of the ID3v2 tag
+    /// `tag`, which may be empty, filling in any that are missing from the
+    /// LAME tag of the first frame in `audio`
+    pub(crate) fn parse(tag: &[u8], audio: &[u8]) -> ReplayGain {
+        let mut replay_gain = ReplayGain::default();
+        for (description, value) in id3v2_user_text(tag) {
+            let field = match description.to_ascii_uppercase().as_str() {
+                "REPLAYGAIN_TRACK_GAIN" => &mut replay_gain.track_gain,
+                "REPLAYGAIN_TRACK_PEAK" => &mut replay_gain.track_peak,
+                "REPLAYGAIN_ALBUM_GAIN" => &mut replay_gain.album_gain,
+                "REPLAYGAIN_ALBUM_PEAK" => &mut replay_gain.album_peak,
+                _ => continue,
+            };
+            // Gains carry a " dB" suffix
+            let value = value
+                .trim()
+                .trim_end_matches(|c: char| c.is_ascii_alphabetic());
+            *field = value.trim().parse().ok();
+        }
+
+        let lame = Decoder::decode_headers(audio).ok().and_then(|mut decoder| {
+            decoder.get_frame().ok()?;
+            parse_xing(decoder.frame_bytes(), decoder.header())?.lame
+        });
+        if let Some(lame) = lame {
+            replay_gain.track_gain = replay_gain.track_gain.or(lame.track_gain);
+            replay_gain.album_gain = replay_gain.album_gain.or(lame.album_gain);
+            replay_gain.track_peak = replay_gain.track_peak.or(lame.peak);
+        }
+
+        replay_gain
+    }
+
+    /// The gain to multiply samples by, reduced if necessary so that the
+    /// stored peak does not clip, or an error if it is too large for the
+    /// fixed-point format
+    pub(crate) fn factor(&self, gain: Gain) -> Result<MadFixed32, SimplemadError> {
+        let (db, peak) = match gain {
+            Gain::Track => (self.track_gain, self.track_peak),
+            Gain::Album if self.album_gain.is_some() => {
+                (self.album_gain, self.album_peak.or(self.track_peak))
+            }
+            Gain::Album => (self.track_gain, self.track_peak),
+            Gain::Manual(db) => (Some(db), self.track_peak),
+        };
+
+        let mut scale = 10f64.powf(db.unwrap_or(0.0) / 20.0);
+        if let Some(peak) = peak {
+            if peak > 0.0 && scale * peak > 1.0 {
+                scale = 1.0 / peak;
+            }
+        }
+        let fixed = (scale * FIXED_ONE).round();
+        if fixed.is_nan() || fixed > f64::from(i32::MAX) {
+            return Err(SimplemadError::Read(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "gain too large",
+            )));
+        }
+        Ok(MadFixed32::new(fixed as i32))
+    }
+}
+
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |acc, &b| (acc << 7) | usize::from(b & 0x7f))
+}
+
+fn big_endian(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | usize::from(b))
+}
+
+/// The description and value of every user defined text frame of an
+/// ID3v2.2, 2.3 or 2.4 tag
+fn id3v2_user_text(tag: &[u8]) -> Vec<(String, String)> {
+    let mut frames = Vec::new();
+    if tag.len() < 10 {
+        return frames;
+    }
+
+    let version = tag[3];
+    let (id_len, header_len, frame_id): (usize, usize, &[u8]) = match version {
+        2 => (3, 6, b"TXX"),
+        3 | 4 => (4, 10, b"TXXX"),
+        _ => return frames,
+    };
+
+    let mut pos = 10;
+    if tag[5] & 0x40 != 0 && version >= 3 {
+        // Skip the extended header, whose size excludes itself in version 2.3
+        pos += match (version, tag.get(10..14)) {
+            (3, Some(size)) => 4 + big_endian(size),
+            (_, Some(size)) => synchsafe(size),
+            _ => return frames,
+        };
+    }
+
+    while let Some(header) = tag.get(pos..pos + header_len) {
+        // Padding follows the last frame
+        if header[0] == 0 {
+            break;
+        }
+        let size_bytes = &header[id_len..id_len + if version == 2 { 3 } else { 4 }];
+        let size = if version == 4 {
+            synchsafe(size_bytes)
+        } else {
+            big_endian(size_bytes)
+        };
+        let body = match tag.get(pos + header_len..pos + header_len + size) {
+            Some(body) => body,
+            None => break,
+        };
+        if &header[..id_len] == frame_id {
+            if let Some(text) = decode_user_text(body) {
+                frames.push(text);
+            }
+        }
+        pos += header_len + size;
+    }
+
+    frames
+}
+
+/// Split the body of a user defined text frame into its description and
+/// value
+fn decode_user_text(body: &[u8]) -> Option<(String, String)> {
+    let (&encoding, text) = body.split_first()?;
+    let strings = match encoding {
+        0 => text.iter().map(|&b| char::from(b)).collect::<String>(),
+        3 => String::from_utf8_lossy(text).into_owned(),
+        1 | 2 => {
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|pair| {
+                    if encoding == 2 {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    }
+                })
+                .collect();
+            // Each string of encoding 1 starts with its own byte order mark
+            let little_endian = units.first() != Some(&0xfffe);
+            let units: Vec<u16> = units
+                .iter()
+                .map(|&u| if little_endian { u } else { u.swap_bytes() })
+                .filter(|&u| u != 0xfeff)
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => return None,
+    };
+
+    let mut parts = strings.splitn(2, '\0');
+    let description = parts.next()?.to_string();
+    let value = parts.next()?.trim_end_matches('\0').to_string();
+    Some((description, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SimplemadError;
+
+    /// An ID3v2 tag holding the given frames
+    fn id3v2_tag(version: u8, frames: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (id, content) in frames {
+            body.extend_from_slice(id.as_bytes());
+            let size = content.len() as u32;
+            if version == 4 {
+                body.extend((0..4).rev().map(|i| (size >> (7 * i)) as u8 & 0x7f));
+            } else {
+                body.extend_from_slice(&size.to_be_bytes());
+            }
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(content);
+        }
+        // Padding
+        body.extend_from_slice(&[0; 16]);
+
+        let size = body.len() as u32;
+        let mut tag = vec![b'I', b'D', b'3', version, 0, 0];
+        tag.extend((0..4).rev().map(|i| (size >> (7 * i)) as u8 & 0x7f));
+        tag.extend_from_slice(&body);
+        tag
+    }
+
+    fn utf8_txxx(description: &str, value: &str) -> Vec<u8> {
+        let mut content = vec![3];
+        content.extend_from_slice(description.as_bytes());
+        content.push(0);
+        content.extend_from_slice(value.as_bytes());
+        content
+    }
+
+    fn utf16_txxx(description: &str, value: &str) -> Vec<u8> {
+        let mut content = vec![1];
+        for text in &[description, value] {
+            content.extend_from_slice(&[0xff, 0xfe]);
+            content.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes()));
+            content.extend_from_slice(&[0, 0]);
+        }
+        content
+    }
+
+    fn sample() -> Vec<u8> {
+        std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap()
+    }
+
+    #[test]
+    fn reads_id3v2_frames() {
+        let tag = id3v2_tag(
+            4,
+            &[
+                ("TIT2", vec![3, b'x']),
+                ("TXXX", utf8_txxx("REPLAYGAIN_TRACK_GAIN", "-6.50 dB")),
+                ("TXXX", utf8_txxx("replaygain_track_peak", "0.988")),
+            ],
+        );
+        let replay_gain = ReplayGain::parse(&tag, &[]);
+        assert_eq!(replay_gain.track_gain, Some(-6.5));
+        assert_eq!(replay_gain.track_peak, Some(0.988));
+        assert_eq!(replay_gain.album_gain, None);
+
+        let tag = id3v2_tag(
+            3,
+            &[("TXXX", utf16_txxx("REPLAYGAIN_ALBUM_GAIN", "+2.10 dB"))],
+        );
+        assert_eq!(ReplayGain::parse(&tag, &[]).album_gain, Some(2.1));
+    }
+
+    #[test]
+    fn reads_lame_tag() {
+        let mut bytes = sample();
+        // Radio gain of -3.2 dB set by the user, and a peak of 0.5
+        let lame = 156;
+        bytes[lame + 11..lame + 15].copy_from_slice(&(1u32 << 22).to_be_bytes());
+        bytes[lame + 15..lame + 17].copy_from_slice(&[0b0010_1110, 32]);
+
+        let replay_gain = ReplayGain::parse(&[], &bytes);
+        assert_eq!(replay_gain.track_gain, Some(-3.2));
+        assert_eq!(replay_gain.track_peak, Some(0.5));
+        assert_eq!(replay_gain.album_gain, None);
+        assert_eq!(ReplayGain::parse(&[], &sample()), ReplayGain::default());
+    }
+
+    #[test]
+    fn prevents_clipping() {
+        let replay_gain = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            album_gain: Some(-6.0),
+            album_peak: None,
+        };
+        let unity = FIXED_ONE as i32;
+        let factor = |replay_gain: &ReplayGain, gain| replay_gain.factor(gain).unwrap();
+        assert_eq!(factor(&replay_gain, Gain::Track).to_raw(), unity * 5 / 4);
+        assert!((factor(&replay_gain, Gain::Album).to_f64() - 0.501).abs() < 0.001);
+        assert_eq!(factor(&ReplayGain::default(), Gain::Album).to_raw(), unity);
+        assert_eq!(factor(&replay_gain, Gain::Manual(0.0)).to_raw(), unity);
+    }
+
+    #[test]
+    fn gain_ceiling() {
+        let replay_gain = ReplayGain::default();
+        let max = replay_gain.factor(Gain::Manual(MAX_GAIN)).unwrap();
+        assert!(f64::from(max.to_raw()) / FIXED_ONE > 7.99);
+        assert!(replay_gain.factor(Gain::Manual(18.1)).is_err());
+        assert!(replay_gain.factor(Gain::Manual(f64::NAN)).is_err());
+
+        let tag = id3v2_tag(4, &[("TXXX", utf8_txxx("REPLAYGAIN_TRACK_GAIN", "+20 dB"))]);
+        let mut bytes = tag;
+        bytes.extend_from_slice(&sample());
+        let decoder = Decoder::decode(&bytes[..]).unwrap();
+        assert!(decoder.with_gain(Gain::Track).is_err());
+    }
+
+    #[test]
+    fn decoder_applies_gain() {
+        let tag = id3v2_tag(
+            4,
+            &[("TXXX", utf8_txxx("REPLAYGAIN_TRACK_GAIN", "-6.0206"))],
+        );
+        let mut bytes = tag;
+        bytes.extend_from_slice(&sample());
+
+        let plain = Decoder::decode(&bytes[..]).unwrap();
+        let mut halved = Decoder::decode(&bytes[..])
+            .unwrap()
+            .with_gain(Gain::Track)
+            .unwrap();
+        assert_eq!(halved.replay_gain().unwrap().track_gain, Some(-6.0206));
+
+        let mut compared = 0;
+        for (a, b) in plain.zip(halved) {
+            let (a, b) = match (a, b) {
+                (Ok(a), Ok(b)) => (a, b),
+                _ => continue,
+            };
+            for (a, b) in a.samples.concat().iter().zip(&b.samples.concat()) {
+                assert!((a.to_raw() / 2 - b.to_raw()).abs() <= 1);
+                compared += 1;
+            }
+        }
+        assert!(compared > 0);
+    }
+
+    #[test]
+    fn reads_tags_larger_than_the_buffer() {
+        // Cover art pushes the ReplayGain frame past the decode buffer
+        let tag = id3v2_tag(
+            3,
+            &[
+                ("APIC", vec![0x55; 100_000]),
+                ("TXXX", utf8_txxx("REPLAYGAIN_TRACK_GAIN", "-6.0206")),
+            ],
+        );
+        let tag_len = tag.len() as u64;
+        let mut bytes = tag;
+        bytes.extend_from_slice(&sample());
+
+        let mut decoder = Decoder::decode(&bytes[..])
+            .unwrap()
+            .with_gain(Gain::Track)
+            .unwrap();
+        assert_eq!(decoder.replay_gain().unwrap().track_gain, Some(-6.0206));
+
+        // Decoding resumes after the tag
+        let offsets: Vec<u64> = decoder.filter_map(|r| r.ok()).map(|f| f.offset).collect();
+        let sample = sample();
+        let plain: Vec<u64> = Decoder::decode(&sample[..])
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .map(|f| f.offset + tag_len)
+            .collect();
+        assert_eq!(offsets, plain);
+    }
+
+    #[test]
+    fn truncated_tag_is_an_error() {
+        let tag = id3v2_tag(3, &[("APIC", vec![0x55; 100_000])]);
+        let result = Decoder::decode(&tag[..50_000])
+            .unwrap()
+            .with_gain(Gain::Track);
+        assert!(matches!(result, Err(SimplemadError::Read(_))));
+    }
+}
@@ -0,0 +1,311 @@
+//! Frame headers parsed in Rust from their four bytes, without libmad
+
+use crate::{MpegVersion, SimplemadError};
+use simplemad_sys::*;
+use std::convert::TryFrom;
+
+/// Bit rates in kbit/s by bit rate index, for MPEG-1 Layers I, II and III
+/// and then MPEG-2 and 2.5 Layer I and Layers II and III
+const BIT_RATES: [[u32; 15]; 5] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// MPEG-1 sample rates by sample rate index, halved for MPEG-2 and
+/// quartered for MPEG-2.5
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// The fields of an MPEG audio frame header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameHeader {
+    /// MPEG version
+    pub version: MpegVersion,
+    /// Audio layer (I, II or III)
+    pub layer: MadLayer,
+    /// Whether a CRC word follows the header
+    pub protected: bool,
+    /// Bit rate in bits per second, or `None` for free format
+    pub bit_rate: Option<u32>,
+    /// Number of samples per second
+    pub sample_rate: u32,
+    /// Whether the frame has an extra slot
+    pub padding: bool,
+    /// The private bit
+    pub private: bool,
+    /// Single Channel, Dual Channel, Joint Stereo or Stereo
+    pub mode: MadMode,
+    /// Joint stereo mode extension bits
+    pub mode_extension: u8,
+    /// Whether the stream is copyright
+    pub copyright: bool,
+    /// Whether the stream is an original rather than a copy
+    pub original: bool,
+    /// De-emphasis to apply
+    pub emphasis: MadEmphasis,
+}
+
+impl FrameHeader {
+    /// Parse the four bytes of a frame header
+    ///
+    /// Fails with the `MadError` that libmad would report: `LostSync`
+    /// without a sync word or for the reserved version, and `BadLayer`,
+    /// `BadBitRate` or `BadSampleRate` for reserved field values.
+    pub fn parse(bytes: [u8; 4]) -> Result<FrameHeader, SimplemadError> {
+        let header = u32::from_be_bytes(bytes);
+        let bits = |shift: u32, len: u32| (header >> shift) & ((1 << len) - 1);
+
+        if bits(21, 11) != 0x7ff {
+            return Err(MadError::LostSync.into());
+        }
+        let version = match bits(19, 2) {
+            0 => MpegVersion::Mpeg25,
+            2 => MpegVersion::Mpeg2,
+            3 => MpegVersion::Mpeg1,
+            _ => return Err(MadError::LostSync.into()),
+        };
+        let layer = match bits(17, 2) {
+            3 => MadLayer::LayerI,
+            2 => MadLayer::LayerII,
+            1 => MadLayer::LayerIII,
+            _ => return Err(MadError::BadLayer.into()),
+        };
+
+        let table = match (version, layer) {
+            (MpegVersion::Mpeg1, MadLayer::LayerI) => 0,
+            (MpegVersion::Mpeg1, MadLayer::LayerII) => 1,
+            (MpegVersion::Mpeg1, MadLayer::LayerIII) => 2,
+            (_, MadLayer::LayerI) => 3,
+            _ => 4,
+        };
+        let bit_rate = match bits(12, 4) {
+            15 => return Err(MadError::BadBitRate.into()),
+            0 => None,
+            index => Some(BIT_RATES[table][index as usize] * 1000),
+        };
+        let sample_rate = match bits(10, 2) {
+            3 => return Err(MadError::BadSampleRate.into()),
+            index => {
+                SAMPLE_RATES[index as usize]
+                    >> match version {
+                        MpegVersion::Mpeg1 => 0,
+                        MpegVersion::Mpeg2 => 1,
+                        MpegVersion::Mpeg25 => 2,
+                    }
+            }
+        };
+
+        Ok(FrameHeader {
+            version,
+            layer,
+            protected: bits(16, 1) == 0,
+            bit_rate,
+            sample_rate,
+            padding: bits(9, 1) == 1,
+            private: bits(8, 1) == 1,
+            // Numbered in reverse by libmad
+            mode: MadMode::try_from(3 - bits(6, 2) as i32).unwrap_or_default(),
+            mode_extension: bits(4, 2) as u8,
+            copyright: bits(3, 1) == 1,
+            original: bits(2, 1) == 1,
+            emphasis: MadEmphasis::try_from(bits(0, 2) as i32).unwrap_or_default(),
+        })
+    }
+
+    /// Length of the frame in bytes, including the header, or `None` for
+    /// free format
+    pub fn frame_len(&self) -> Option<usize> {
+        let bit_rate = self.bit_rate?;
+        Some(frame_len(
+            self.version,
+            self.layer,
+            bit_rate,
+            self.sample_rate,
+            self.padding,
+        ))
+    }
+
+    /// Number of samples per channel in the frame
+    pub fn samples_per_frame(&self) -> usize {
+        match (self.layer, self.version) {
+            (MadLayer::LayerI, _) => 384,
+            (MadLayer::LayerIII, MpegVersion::Mpeg2)
+            | (MadLayer::LayerIII, MpegVersion::Mpeg25) => 576,
+            _ => 1152,
+        }
+    }
+}
+
+/// Length in bytes of a frame with the given bit rate in bits per second,
+/// including the header
+pub fn frame_len(
+    version: MpegVersion,
+    layer: MadLayer,
+    bit_rate: u32,
+    sample_rate: u32,
+    padding: bool,
+) -> usize {
+    if sample_rate == 0 {
+        return 0;
+    }
+    let (bit_rate, sample_rate, padding) = (
+        u64::from(bit_rate),
+        u64::from(sample_rate),
+        u64::from(padding),
+    );
+    let len = match (layer, version) {
+        // Layer I counts in slots of four bytes
+        (MadLayer::LayerI, _) => (12 * bit_rate / sample_rate + padding) * 4,
+        (MadLayer::LayerIII, MpegVersion::Mpeg2) | (MadLayer::LayerIII, MpegVersion::Mpeg25) => {
+            72 * bit_rate / sample_rate + padding
+        }
+        _ => 144 * bit_rate / sample_rate + padding,
+    };
+    len as usize
+}
+
+/// Whether the `count` frame headers that follow the frame at the start of
+/// `bytes`, `frame_len` bytes long, agree with its version, layer and
+/// sample rate, or `None` if `bytes` ends first
+pub(crate) fn headers_agree(bytes: &[u8], frame_len: usize, count: usize) -> Option<bool> {
+    let header_at = |pos: usize| {
+        let bytes = bytes.get(pos..pos + 4)?;
+        Some(FrameHeader::parse([bytes[0], bytes[1], bytes[2], bytes[3]]).ok())
+    };
+
+    let first = match header_at(0)? {
+        Some(header) => header,
+        None => return Some(false),
+    };
+    let mut pos = frame_len;
+    for _ in 0..count {
+        let header = match header_at(pos)? {
+            Some(header) => header,
+            None => return Some(false),
+        };
+        if (header.version, header.layer, header.sample_rate)
+            != (first.version, first.layer, first.sample_rate)
+        {
+            return Some(false);
+        }
+        // Free format frames keep the length libmad found for the first
+        pos += header
+            .frame_len()
+            .unwrap_or(frame_len - usize::from(first.padding) + usize::from(header.padding));
+    }
+    Some(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Decoder;
+    use std::fs;
+
+    #[test]
+    fn parses_fields() {
+        let header = FrameHeader::parse([0xff, 0xfb, 0x96, 0x64]).unwrap();
+        assert_eq!(header.version, MpegVersion::Mpeg1);
+        assert_eq!(header.layer, MadLayer::LayerIII);
+        assert!(!header.protected);
+        assert_eq!(header.bit_rate, Some(128_000));
+        assert_eq!(header.sample_rate, 48000);
+        assert!(header.padding);
+        assert_eq!(header.mode, MadMode::JointStereo);
+        assert_eq!(header.mode_extension, 2);
+        assert!(header.original);
+        assert_eq!(header.frame_len(), Some(385));
+
+        let header = FrameHeader::parse([0xff, 0xe2, 0x10, 0xc0]).unwrap();
+        assert_eq!(header.version, MpegVersion::Mpeg25);
+        assert_eq!(header.layer, MadLayer::LayerIII);
+        assert!(header.protected);
+        assert_eq!(header.bit_rate, Some(8000));
+        assert_eq!(header.sample_rate, 11025);
+        assert_eq!(header.mode, MadMode::SingleChannel);
+        assert_eq!(header.samples_per_frame(), 576);
+        assert_eq!(header.frame_len(), Some(52));
+
+        let free = FrameHeader::parse([0xff, 0xfd, 0x00, 0x00]).unwrap();
+        assert_eq!((free.layer, free.bit_rate), (MadLayer::LayerII, None));
+        assert_eq!(free.frame_len(), None);
+    }
+
+    #[test]
+    fn rejects_reserved_values() {
+        let error = |bytes| match FrameHeader::parse(bytes) {
+            Err(SimplemadError::Mad(error)) => error,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error([0xff, 0x7b, 0x90, 0x00]), MadError::LostSync);
+        assert_eq!(error([0xff, 0xeb, 0x90, 0x00]), MadError::LostSync);
+        assert_eq!(error([0xff, 0xf9, 0x90, 0x00]), MadError::BadLayer);
+        assert_eq!(error([0xff, 0xfb, 0xf0, 0x00]), MadError::BadBitRate);
+        assert_eq!(error([0xff, 0xfb, 0x9c, 0x00]), MadError::BadSampleRate);
+    }
+
+    #[test]
+    fn frame_lengths() {
+        use MadLayer::*;
+        use MpegVersion::*;
+        assert_eq!(frame_len(Mpeg1, LayerIII, 128_000, 44100, false), 417);
+        assert_eq!(frame_len(Mpeg1, LayerIII, 128_000, 44100, true), 418);
+        assert_eq!(frame_len(Mpeg1, LayerII, 192_000, 48000, false), 576);
+        assert_eq!(frame_len(Mpeg1, LayerI, 32_000, 32000, true), 52);
+        assert_eq!(frame_len(Mpeg2, LayerI, 256_000, 24000, false), 512);
+        assert_eq!(frame_len(Mpeg2, LayerII, 160_000, 22050, false), 1044);
+        assert_eq!(frame_len(Mpeg2, LayerIII, 64_000, 22050, true), 209);
+        assert_eq!(frame_len(Mpeg25, LayerIII, 8_000, 8000, false), 72);
+    }
+
+    /// Every header libmad decodes from the samples parses to the same
+    /// fields and frame length
+    #[test]
+    fn matches_libmad() {
+        let mut checked = 0;
+        for entry in fs::read_dir("sample_mp3s").unwrap() {
+            let data = fs::read(entry.unwrap().path()).unwrap();
+            let mut decoder = Decoder::decode_headers(&data[..]).unwrap();
+            loop {
+                match decoder.get_frame() {
+                    Ok(_) => {}
+                    Err(SimplemadError::Mad(_)) => continue,
+                    Err(_) => break,
+                }
+                let bytes = decoder.frame_bytes();
+                let header = FrameHeader::parse([bytes[0], bytes[1], bytes[2], bytes[3]]).unwrap();
+                let mad = decoder.header();
+
+                assert_eq!(header.version, MpegVersion::from_header(mad));
+                assert_eq!(Ok(header.layer), mad.layer());
+                assert_eq!(Ok(header.mode), mad.mode());
+                assert_eq!(Ok(header.emphasis), mad.emphasis());
+                assert_eq!(header.mode_extension as i32, mad.mode_extension);
+                assert_eq!(header.sample_rate, mad.sample_rate);
+                assert_eq!(header.bit_rate, Some(mad.bit_rate as u32));
+                assert_eq!(header.protected, mad.flags & MAD_FLAG_PROTECTION != 0);
+                assert_eq!(header.padding, mad.flags & MAD_FLAG_PADDING != 0);
+                assert_eq!(header.copyright, mad.flags & MAD_FLAG_COPYRIGHT != 0);
+                assert_eq!(header.original, mad.flags & MAD_FLAG_ORIGINAL != 0);
+                assert_eq!(header.private, mad.private_bits != 0);
+                assert_eq!(header.frame_len(), Some(bytes.len()));
+                assert_eq!(
+                    header.samples_per_frame() as u64,
+                    crate::frame_sample_count(mad)
+                );
+                checked += 1;
+            }
+        }
+        assert!(checked > 1000);
+    }
+}
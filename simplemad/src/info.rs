@@ -0,0 +1,57 @@
+//! Details of the libmad build in use, for bug reports and support bundles
+
+use simplemad_sys::*;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// The version and build options of the linked libmad
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LibmadInfo {
+    /// Version string, such as "MPEG Audio Decoder 0.15.1 (beta)"
+    pub version: String,
+    /// Copyright notice
+    pub copyright: String,
+    /// Author and contact address
+    pub author: String,
+    /// Options libmad was compiled with, such as "FPM_64BIT OPT_SSO"
+    pub build: String,
+    /// Whether libmad was compiled from the sources bundled with
+    /// `simplemad_sys` rather than linked from the system
+    pub vendored: bool,
+}
+
+/// Report which libmad build is decoding
+pub fn libmad_info() -> LibmadInfo {
+    let string = |s: &[c_char; 0]| {
+        unsafe { CStr::from_ptr(s.as_ptr()) }
+            .to_string_lossy()
+            .trim()
+            .to_string()
+    };
+    unsafe {
+        LibmadInfo {
+            version: string(&mad_version),
+            copyright: string(&mad_copyright),
+            author: string(&mad_author),
+            build: string(&mad_build),
+            vendored: build_config().vendored,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports_build() {
+        let info = libmad_info();
+        assert!(info.version.starts_with("MPEG Audio Decoder 0.15"));
+        assert!(info.copyright.starts_with("Copyright"));
+        if info.vendored {
+            let fpm = build_config().fpm.unwrap();
+            assert!(info.build.split(' ').any(|option| option == fpm));
+        }
+    }
+}
@@ -56,10 +56,48 @@
 #![deny(missing_docs, trivial_casts, unstable_features, unused_import_braces)]
 
 extern crate simplemad_sys;
+
+pub mod analysis;
+mod ancillary;
+mod callback;
+mod crc;
+mod gain;
+mod header;
+mod info;
+#[cfg(feature = "playback")]
+pub mod playback;
+mod probe;
+pub mod raw;
+pub mod resample;
+mod seek_index;
+#[cfg(feature = "rodio")]
+mod source;
+mod splice;
+mod validate;
+pub mod wav;
+pub mod waveform;
+mod xing;
+
+pub use ancillary::AncillaryData;
+pub use callback::CallbackDecoder;
+pub use gain::{Gain, ReplayGain, MAX_GAIN};
+pub use header::{frame_len, FrameHeader};
+pub use info::{libmad_info, LibmadInfo};
+pub use probe::{probe, BitRateMode, BitRateStats, InfoSource, MpegVersion, StreamInfo};
+pub use seek_index::{SeekIndex, SeekPoint};
+#[cfg(feature = "rodio")]
+pub use source::DecoderSource;
+pub use splice::{splice, Segment};
+pub use validate::{validate, validate_full, Issue, IssueKind, ValidationReport};
+
+use raw::{FrameDecoder, Stream, Synth};
 use simplemad_sys::*;
 use std::cmp::{max, min};
 use std::default::Default;
-use std::io;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::ops;
 use std::time::Duration;
 
 /// A decoded frame
@@ -80,6 +118,14 @@ pub struct Frame {
     pub duration: Duration,
     /// The position at the start of the frame
     pub position: Duration,
+    /// Byte offset of the frame in the input
+    pub offset: u64,
+    /// Whether the frame matches its CRC word, if it has one and checking
+    /// was enabled with `Decoder::with_crc_check`
+    pub crc_ok: Option<bool>,
+    /// The ancillary data after the audio data, if enabled with
+    /// `Decoder::with_ancillary_data`
+    pub ancillary: Option<AncillaryData>,
 }
 
 /// An interface for the decoding operation
@@ -92,13 +138,27 @@ where
 {
     reader: R,
     buffer: Box<[u8; 0x8000]>,
-    stream: MadStream,
-    synth: MadSynth,
-    frame: MadFrame,
+    // Reads from `buffer`, which is only changed before the stream is
+    // given it again
+    stream: Stream<'static>,
+    synth: Synth,
+    frame: FrameDecoder,
     position: Duration,
+    buffer_offset: u64,
     headers_only: bool,
     start_time: Option<Duration>,
     end_time: Option<Duration>,
+    precise: bool,
+    sample_position: u64,
+    header_pending: bool,
+    primed: bool,
+    index: Option<SeekIndex>,
+    crc_check: bool,
+    gain: Option<MadFixed32>,
+    replay_gain: Option<ReplayGain>,
+    strict_sync: Option<usize>,
+    synced: bool,
+    ancillary: bool,
 }
 
 impl<R> Decoder<R>
@@ -110,6 +170,7 @@ where
         start_time: Option<Duration>,
         end_time: Option<Duration>,
         headers_only: bool,
+        precise: bool,
     ) -> Result<Decoder<R>, SimplemadError> {
         let mut new_decoder = Decoder {
             reader,
@@ -118,22 +179,29 @@ where
             synth: Default::default(),
             frame: Default::default(),
             position: Duration::new(0, 0),
+            buffer_offset: 0,
             headers_only,
             start_time,
             end_time,
+            precise,
+            sample_position: 0,
+            header_pending: false,
+            primed: false,
+            index: None,
+            crc_check: false,
+            gain: None,
+            replay_gain: None,
+            strict_sync: None,
+            synced: false,
+            ancillary: false,
         };
 
         let bytes_read = new_decoder.reader.read(&mut *new_decoder.buffer)?;
 
         unsafe {
-            mad_stream_init(&mut new_decoder.stream);
-            mad_frame_init(&mut new_decoder.frame);
-            mad_synth_init(&mut new_decoder.synth);
-            mad_stream_buffer(
-                &new_decoder.stream,
-                new_decoder.buffer.as_ptr(),
-                bytes_read as c_ulong,
-            );
+            new_decoder
+                .stream
+                .set_buffer_ptr(new_decoder.buffer.as_ptr(), bytes_read);
         }
 
         Ok(new_decoder)
@@ -141,35 +209,145 @@ where
 
     /// Decode a file in full
     pub fn decode(reader: R) -> Result<Decoder<R>, SimplemadError> {
-        Decoder::new(reader, None, None, false)
+        Decoder::new(reader, None, None, false, false)
     }
 
     /// Decode only the header information of each frame
     pub fn decode_headers(reader: R) -> Result<Decoder<R>, SimplemadError> {
-        Decoder::new(reader, None, None, true)
+        Decoder::new(reader, None, None, true, false)
     }
 
     /// Decode part of a file from `start_time` to `end_time`
+    ///
+    /// Every frame that starts within the interval is returned in full.
+    /// The frames ahead of `start_time` are decoded and discarded to prime
+    /// the decoder, so the output matches that of a linear decode.
     pub fn decode_interval(
         reader: R,
         start_time: Duration,
         end_time: Duration,
     ) -> Result<Decoder<R>, SimplemadError> {
-        Decoder::new(reader, Some(start_time), Some(end_time), false)
+        Decoder::new(reader, Some(start_time), Some(end_time), false, false)
     }
 
-    /// Get the next decoding result, either a `Frame` or a `SimplemadError`
-    pub fn get_frame(&mut self) -> Result<Frame, SimplemadError> {
-        if let Some(t) = self.start_time {
-            if self.position < t {
-                return self.seek_to_start();
-            }
+    /// Decode part of a file from `start_time` to `end_time`, trimming
+    /// the first and last frames so that the samples begin and end
+    /// exactly at the requested times
+    ///
+    /// Priming works as for `decode_interval`. Assumes the sample rate
+    /// does not change within the interval.
+    pub fn decode_interval_precise(
+        reader: R,
+        start_time: Duration,
+        end_time: Duration,
+    ) -> Result<Decoder<R>, SimplemadError> {
+        Decoder::new(reader, Some(start_time), Some(end_time), false, true)
+    }
+
+    /// Verify the CRC of every protected frame, reporting the result in
+    /// `Frame::crc_ok`
+    ///
+    /// This works for header-only decoding too. Frames that fail the check
+    /// are still decoded, where libmad would otherwise drop them with
+    /// `MadError::BadCRC`.
+    pub fn with_crc_check(mut self) -> Decoder<R> {
+        self.crc_check = true;
+        self.stream
+            .set_options(self.stream.options() | MAD_OPTION_IGNORECRC);
+        self
+    }
+
+    /// Have libmad synthesize every frame at half its sample rate, which is
+    /// faster and reported in `Frame::sample_rate`
+    pub fn with_half_sample_rate(mut self) -> Decoder<R> {
+        self.stream
+            .set_options(self.stream.options() | MAD_OPTION_HALFSAMPLERATE);
+        self
+    }
+
+    /// Multiply every decoded sample by a gain, limited so that the peak
+    /// stored with the ReplayGain values does not clip
+    ///
+    /// Call this before decoding any frames, as the gain is looked up in
+    /// `replay_gain`. Missing values leave the samples unchanged. Gains
+    /// above `MAX_GAIN` dB do not fit libmad's fixed-point format and are
+    /// an error.
+    pub fn with_gain(mut self, gain: Gain) -> Result<Decoder<R>, SimplemadError> {
+        self.gain = Some(self.replay_gain()?.factor(gain)?);
+        Ok(self)
+    }
+
+    /// Accept a sync point only if the next `headers` frame headers, at the
+    /// offsets given by the frame lengths, agree with it on the MPEG
+    /// version, layer and sample rate
+    ///
+    /// Junk such as embedded album art can hold patterns that libmad takes
+    /// for frames. With strict sync these are skipped, along with the
+    /// errors they cause, both at the start and wherever libmad loses sync.
+    /// Near the end of the stream the headers that remain must agree, and
+    /// elsewhere a sync point is skipped if its headers do not fit in the
+    /// decoder's buffer.
+    pub fn with_strict_sync(mut self, headers: usize) -> Decoder<R> {
+        self.strict_sync = Some(headers);
+        self
+    }
+
+    /// Report the ancillary data of every decoded frame in
+    /// `Frame::ancillary`
+    ///
+    /// Header-only decoding does not reach the ancillary data, and reports
+    /// `None`.
+    pub fn with_ancillary_data(mut self) -> Decoder<R> {
+        self.ancillary = true;
+        self
+    }
+
+    /// The ReplayGain values in an ID3v2 tag or LAME tag at the start of
+    /// the stream
+    ///
+    /// The first call reads the whole ID3v2 tag, however large, and
+    /// decoding continues after it. This fails if the tag is cut short, or
+    /// if decoding has already started.
+    pub fn replay_gain(&mut self) -> Result<ReplayGain, SimplemadError> {
+        if let Some(replay_gain) = self.replay_gain {
+            return Ok(replay_gain);
+        }
+        if self.buffer_offset != 0 || self.stream.next_frame() != 0 {
+            return Err(SimplemadError::Read(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ReplayGain values are read before decoding starts",
+            )));
         }
 
-        if let Some(t) = self.end_time {
-            if self.position >= t {
-                return Err(SimplemadError::EOF);
+        let data_len = self.stream.buffer_len();
+        let tag_len = probe::id3v2_size(&self.buffer[..data_len]) as usize;
+        let replay_gain = if tag_len <= data_len {
+            ReplayGain::parse(&self.buffer[..tag_len], &self.buffer[tag_len..data_len])
+        } else {
+            // Cover art can make the tag much larger than the buffer
+            let mut tag = self.buffer[..data_len].to_vec();
+            (&mut self.reader)
+                .take((tag_len - data_len) as u64)
+                .read_to_end(&mut tag)?;
+            if tag.len() < tag_len {
+                return Err(SimplemadError::Read(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the ID3v2 tag is cut short",
+                )));
             }
+            self.buffer_offset += (tag_len - data_len) as u64;
+            self.shift_buffer(data_len)?;
+            ReplayGain::parse(&tag, &self.buffer[..self.stream.buffer_len()])
+        };
+
+        self.replay_gain = Some(replay_gain);
+        Ok(replay_gain)
+    }
+
+    /// Get the next decoding result, either a `Frame` or a `SimplemadError`
+    pub fn get_frame(&mut self) -> Result<Frame, SimplemadError> {
+        if let Some(start_time) = self.start_time {
+            return self.get_interval_frame(start_time, self.end_time);
         }
 
         let decoding_result = if self.headers_only {
@@ -180,7 +358,7 @@ where
 
         match decoding_result {
             Ok(frame) => {
-                self.position += frame_duration(&self.frame);
+                self.position += frame_duration(self.frame.header());
                 Ok(frame)
             }
             Err(SimplemadError::Mad(MadError::BufLen)) => {
@@ -191,68 +369,238 @@ where
                     self.get_frame()
                 }
             }
+            Err(SimplemadError::Mad(MadError::LostSync)) if self.strict_sync.is_some() => {
+                self.synced = false;
+                self.get_frame()
+            }
             Err(e) => Err(e),
         }
     }
 
-    fn seek_to_start(&mut self) -> Result<Frame, SimplemadError> {
-        if let Some(start_time) = self.start_time {
-            while self.position < start_time {
-                match self.decode_header_only() {
-                    Ok(frame) => {
-                        self.position += frame.duration;
+    fn get_interval_frame(
+        &mut self,
+        start_time: Duration,
+        end_time: Option<Duration>,
+    ) -> Result<Frame, SimplemadError> {
+        if !self.primed {
+            self.seek_to_start(start_time)?;
+            self.primed = true;
+        }
+
+        loop {
+            let frame_start = self.sample_position;
+            let mut frame = match self.decode_next() {
+                Ok(frame) => frame,
+                Err(SimplemadError::Mad(MadError::BufLen)) => {
+                    if self.refill_buffer()? == 0 {
+                        return Err(SimplemadError::EOF);
                     }
-                    Err(SimplemadError::Mad(MadError::BufLen)) => {
-                        if self.refill_buffer()? == 0 {
-                            return Err(SimplemadError::EOF);
-                        }
+                    continue;
+                }
+                Err(SimplemadError::Mad(MadError::LostSync)) if self.strict_sync.is_some() => {
+                    self.synced = false;
+                    continue;
+                }
+                Err(SimplemadError::Mad(error)) => {
+                    // Errors past the header stage still consume a frame
+                    if (error as i32) >= 0x0200 {
+                        self.advance_precise_position();
                     }
-                    Err(e) => return Err(e),
+                    let start_sample =
+                        duration_to_samples(start_time, self.frame.header().sample_rate);
+                    if self.is_priming(frame_start, start_sample) {
+                        continue;
+                    }
+                    return Err(SimplemadError::Mad(error));
                 }
+                Err(e) => return Err(e),
+            };
+
+            self.advance_precise_position();
+
+            let sample_rate = frame.sample_rate;
+            let start_sample = duration_to_samples(start_time, sample_rate);
+            let end_sample = end_time.map_or(u64::MAX, |t| duration_to_samples(t, sample_rate));
+
+            if self.is_priming(frame_start, start_sample) {
+                continue;
+            }
+
+            let first = max(start_sample, frame_start);
+            let last = min(end_sample, self.sample_position);
+            if first >= last {
+                return Err(SimplemadError::EOF);
+            }
+
+            if self.precise {
+                let skip = (first - frame_start) as usize;
+                let keep = (last - first) as usize;
+                for channel in &mut frame.samples {
+                    channel.drain(..skip);
+                    channel.truncate(keep);
+                }
+                frame.position = samples_to_duration(first, sample_rate);
+                frame.duration = samples_to_duration(keep as u64, sample_rate);
+            } else {
+                frame.position = samples_to_duration(frame_start, sample_rate);
             }
+
+            return Ok(frame);
         }
+    }
 
-        self.get_frame()
+    /// Whether the frame that began at `frame_start` only primes the
+    /// decoder for an interval starting at `start_sample`
+    ///
+    /// Precise decoding trims the frame that contains the start, while
+    /// `decode_interval` returns the frames that begin within the interval.
+    fn is_priming(&self, frame_start: u64, start_sample: u64) -> bool {
+        if self.precise {
+            self.sample_position <= start_sample
+        } else {
+            frame_start < start_sample
+        }
+    }
+
+    /// Skip frame headers until just enough frames remain before
+    /// `start_time` to prime the decoder, so that the first frame at
+    /// `start_time` decodes exactly as it would in a linear decode
+    fn seek_to_start(&mut self, start_time: Duration) -> Result<(), SimplemadError> {
+        loop {
+            let header = self.frame.header();
+            if self.header_pending {
+                // Stop once the pending frame is the first one needed for priming
+                let frame_len = frame_sample_count(header);
+                let start_sample = duration_to_samples(start_time, header.sample_rate);
+                let lead = frame_len * (priming_frames(header) + 1);
+                if self.sample_position + lead > start_sample {
+                    return Ok(());
+                }
+
+                // The pending frame is skipped by the next header decode,
+                // even if that fails
+                self.advance_precise_position();
+                self.header_pending = false;
+            }
+
+            match self.next_header() {
+                Ok(()) => self.header_pending = true,
+                Err(SimplemadError::Mad(MadError::BufLen)) => {
+                    if self.refill_buffer()? == 0 {
+                        return Err(SimplemadError::EOF);
+                    }
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn advance_precise_position(&mut self) {
+        let header = self.frame.header();
+        self.sample_position += frame_sample_count(header);
+        self.position = samples_to_duration(self.sample_position, header.sample_rate);
+    }
+
+    /// Decode the next frame, or just its header, picking up any frame
+    /// whose header was decoded by `seek_to_start`
+    fn decode_next(&mut self) -> Result<Frame, SimplemadError> {
+        let header_pending = self.header_pending;
+        self.header_pending = false;
+
+        if !self.headers_only {
+            self.decode_frame()
+        } else if header_pending {
+            self.header_frame()
+        } else {
+            self.decode_header_only()
+        }
     }
 
     fn decode_header_only(&mut self) -> Result<Frame, SimplemadError> {
-        unsafe {
-            mad_header_decode(&mut self.frame.header, &mut self.stream);
+        self.next_header()?;
+        self.header_frame()
+    }
+
+    /// Decode the next frame header, first finding a sync point if strict
+    /// sync is on and the decoder is not synced
+    fn next_header(&mut self) -> Result<(), SimplemadError> {
+        match self.strict_sync {
+            Some(headers) if !self.synced => self.sync_strictly(headers),
+            _ => self.frame.decode_header(&mut self.stream).map(|_| ()),
         }
+    }
 
-        if let Some(error) = self.check_error() {
-            return Err(SimplemadError::Mad(error));
+    /// Decode frame headers until one is followed by `headers` headers
+    /// that agree with it, leaving it pending
+    fn sync_strictly(&mut self, headers: usize) -> Result<(), SimplemadError> {
+        let mut exhausted = false;
+        loop {
+            match self.frame.decode_header(&mut self.stream) {
+                Ok(_) => {}
+                Err(SimplemadError::Mad(MadError::BufLen)) => {
+                    return Err(SimplemadError::Mad(MadError::BufLen))
+                }
+                // Errors while looking for a sync point come from junk
+                Err(SimplemadError::Mad(_)) | Err(SimplemadError::Unknown(_)) => continue,
+                Err(e) => return Err(e),
+            }
+
+            let start = self.stream.this_frame();
+            let data = &self.buffer[start..self.stream.buffer_len()];
+            let frame_len = self.stream.next_frame() - start;
+            match header::headers_agree(data, frame_len, headers) {
+                Some(true) => {}
+                // Accept what there is at the end of the stream
+                None if exhausted => {}
+                // The headers lie beyond a full buffer, so cannot be checked
+                None if start == 0 && data.len() == self.buffer.len() => {
+                    self.stream.resync(1);
+                    continue;
+                }
+                None => {
+                    // Fetch more data and decode the same header again
+                    exhausted = self.shift_buffer(start)? == 0;
+                    continue;
+                }
+                Some(false) => {
+                    // Look for a sync point from the next byte
+                    self.stream.resync(start + 1);
+                    continue;
+                }
+            }
+
+            self.synced = true;
+            return Ok(());
         }
+    }
 
+    fn header_frame(&self) -> Result<Frame, SimplemadError> {
+        let header = self.frame.header();
+        let (layer, mode) = header_enums(header)?;
         Ok(Frame {
-            sample_rate: self.frame.header.sample_rate,
-            mode: self.frame.header.mode,
-            layer: self.frame.header.layer,
-            bit_rate: self.frame.header.bit_rate as u32,
+            sample_rate: header.sample_rate,
+            mode,
+            layer,
+            bit_rate: header.bit_rate as u32,
             samples: Vec::new(),
-            duration: frame_duration(&self.frame),
+            duration: frame_duration(header),
             position: self.position,
+            offset: self.frame_offset(),
+            crc_ok: self.frame_crc(),
+            ancillary: None,
         })
     }
 
     fn decode_frame(&mut self) -> Result<Frame, SimplemadError> {
-        unsafe {
-            mad_frame_decode(&mut self.frame, &mut self.stream);
+        if self.strict_sync.is_some() && !self.synced {
+            // Leaves the header pending for the frame decode
+            self.next_header()?;
         }
+        let header = *self.frame.decode(&mut self.stream)?;
+        let (layer, mode) = header_enums(&header)?;
 
-        if let Some(error) = self.check_error() {
-            return Err(SimplemadError::Mad(error));
-        }
-
-        unsafe {
-            mad_synth_frame(&mut self.synth, &mut self.frame);
-        }
-
-        if let Some(error) = self.check_error() {
-            return Err(SimplemadError::Mad(error));
-        }
-
-        let pcm = &self.synth.pcm;
+        let pcm = self.synth.synthesize(&self.frame);
+        let gain = self.gain;
         let samples = pcm
             .samples
             .iter()
@@ -260,26 +608,41 @@ where
             .map(|ch| {
                 ch.iter()
                     .take(pcm.length as usize)
-                    .map(|sample| MadFixed32::new(*sample))
+                    .map(|sample| match gain {
+                        Some(gain) => MadFixed32::new(*sample) * gain,
+                        None => MadFixed32::new(*sample),
+                    })
                     .collect()
             })
             .collect();
 
         Ok(Frame {
             sample_rate: pcm.sample_rate,
-            duration: frame_duration(&self.frame),
-            mode: self.frame.header.mode,
-            layer: self.frame.header.layer,
-            bit_rate: self.frame.header.bit_rate as u32,
+            duration: frame_duration(&header),
+            mode,
+            layer,
+            bit_rate: header.bit_rate as u32,
             position: self.position,
+            offset: self.frame_offset(),
+            crc_ok: self.frame_crc(),
+            ancillary: self.ancillary.then(|| self.stream.ancillary_data()),
             samples,
         })
     }
 
     fn refill_buffer(&mut self) -> Result<usize, io::Error> {
+        self.shift_buffer(self.stream.next_frame())
+    }
+
+    /// Drop the data before `position` in the buffer and fill the rest from
+    /// the reader, returning the number of bytes read
+    fn shift_buffer(&mut self, position: usize) -> Result<usize, io::Error> {
         let buffer_len = self.buffer.len();
-        let next_frame_position = self.stream.next_frame as usize - self.stream.buffer as usize;
-        let unused_byte_count = buffer_len - min(next_frame_position, buffer_len);
+        let data_len = self.stream.buffer_len();
+        let next_frame_position = min(position, data_len);
+        let unused_byte_count = data_len - next_frame_position;
+
+        self.buffer_offset += next_frame_position as u64;
 
         // Shift unused data to front of buffer
         for idx in 0..unused_byte_count {
@@ -297,26 +660,81 @@ where
         }
 
         unsafe {
-            mad_stream_buffer(
-                &self.stream,
-                self.buffer.as_ptr(),
-                free_region_start as c_ulong,
-            );
+            self.stream
+                .set_buffer_ptr(self.buffer.as_ptr(), free_region_start);
         }
 
         let bytes_read = free_region_start - unused_byte_count;
         Ok(bytes_read)
     }
 
-    fn check_error(&mut self) -> Option<MadError> {
-        if self.stream.error != MadError::None {
-            let error = self.stream.error;
-            self.stream.error = MadError::None;
-            Some(error)
+    /// Byte offset in the input of the frame most recently decoded, or
+    /// being decoded when an error occurred
+    pub fn frame_offset(&self) -> u64 {
+        self.buffer_offset + self.stream.this_frame() as u64
+    }
+
+    fn frame_crc(&self) -> Option<bool> {
+        if self.crc_check {
+            crc::check_crc(self.frame_bytes(), self.frame.header())
         } else {
             None
         }
     }
+
+    /// The header of the most recently decoded frame
+    pub(crate) fn header(&self) -> &MadHeader {
+        self.frame.header()
+    }
+
+    /// The raw bytes of the most recently decoded frame
+    pub(crate) fn frame_bytes(&self) -> &[u8] {
+        self.stream.frame_bytes()
+    }
+}
+
+impl<R> Decoder<R>
+where
+    R: io::Read + io::Seek,
+{
+    /// Decode a file in full, using a prebuilt `SeekIndex` to find seek
+    /// points
+    pub fn decode_with_index(reader: R, index: SeekIndex) -> Result<Decoder<R>, SimplemadError> {
+        let mut decoder = Decoder::new(reader, None, None, false, false)?;
+        decoder.index = Some(index);
+        Ok(decoder)
+    }
+
+    /// Seek so that the next frame begins exactly at `position`
+    ///
+    /// Decoding restarts from the closest suitable point in the seek index,
+    /// or from the start of the reader if there is no index, and is primed
+    /// as for `decode_interval`. From then on frames are trimmed as for
+    /// `decode_interval_precise`.
+    pub fn seek(&mut self, position: Duration) -> Result<(), SimplemadError> {
+        let (point, sample_rate) = match self.index {
+            Some(ref index) => (index.point_before(position), index.sample_rate()),
+            None => (SeekPoint::default(), 0),
+        };
+
+        self.reader.seek(io::SeekFrom::Start(point.offset))?;
+
+        self.stream.reset();
+        self.frame.mute();
+        self.synth.mute();
+
+        self.buffer_offset = point.offset;
+        self.sample_position = point.sample;
+        self.position = samples_to_duration(point.sample, sample_rate);
+        self.start_time = Some(position);
+        self.precise = true;
+        self.header_pending = false;
+        self.primed = false;
+        self.synced = false;
+
+        self.refill_buffer()?;
+        Ok(())
+    }
 }
 
 impl<R> Iterator for Decoder<R>
@@ -333,21 +751,6 @@ where
     }
 }
 
-impl<R> Drop for Decoder<R>
-where
-    R: io::Read,
-{
-    fn drop(&mut self) {
-        unsafe {
-            mad_stream_finish(&mut self.stream);
-            mad_frame_finish(&mut self.frame);
-            // mad_synth_finish is present in the libmad docs
-            // but is defined as nothing in the library
-            // mad_synth_finish(&mut self.synth);
-        }
-    }
-}
-
 #[derive(Debug)]
 /// An error encountered during the decoding process
 pub enum SimplemadError {
@@ -355,8 +758,34 @@ pub enum SimplemadError {
     Read(io::Error),
     /// A `MadError` generated by libmad
     Mad(MadError),
+    /// An error code or header field from libmad with a value that has no
+    /// matching variant
+    Unknown(i32),
     /// The `Reader` has stopped producing data
     EOF,
+    /// A closure of `CallbackDecoder` returned `MadFlow::Break`
+    Break,
+}
+
+impl fmt::Display for SimplemadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimplemadError::Read(err) => write!(f, "Read error: {}", err),
+            SimplemadError::Mad(err) => write!(f, "libmad error: {:?}", err),
+            SimplemadError::Unknown(value) => write!(f, "Unknown libmad value: {:#x}", value),
+            SimplemadError::EOF => write!(f, "End of stream"),
+            SimplemadError::Break => write!(f, "Decoding stopped by a callback"),
+        }
+    }
+}
+
+impl Error for SimplemadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SimplemadError::Read(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<MadError> for SimplemadError {
@@ -371,8 +800,81 @@ impl From<io::Error> for SimplemadError {
     }
 }
 
-fn frame_duration(frame: &MadFrame) -> Duration {
-    let duration = &frame.header.duration;
+/// The layer and mode of a decoded header
+fn header_enums(header: &MadHeader) -> Result<(MadLayer, MadMode), SimplemadError> {
+    let layer = header.layer().map_err(SimplemadError::Unknown)?;
+    let mode = header.mode().map_err(SimplemadError::Unknown)?;
+    Ok((layer, mode))
+}
+
+/// Value of 1.0 in libmad's fixed-point format
+pub(crate) const FIXED_ONE: f64 = (1 << 28) as f64;
+
+/// Number of zero bytes libmad needs after the last frame of a stream to
+/// decode it, which `Decoder` does not add by itself
+pub(crate) const BUFFER_GUARD: usize = 8;
+
+pub(crate) fn frame_sample_count(header: &MadHeader) -> u64 {
+    let duration = &header.duration;
+    let sample_rate = u64::from(header.sample_rate);
+    duration.seconds as u64 * sample_rate + duration.fraction * sample_rate / MAD_TIMER_RESOLUTION
+}
+
+pub(crate) fn duration_to_samples(duration: Duration, sample_rate: u32) -> u64 {
+    let sample_rate = u64::from(sample_rate);
+    duration.as_secs() * sample_rate
+        + u64::from(duration.subsec_nanos()) * sample_rate / 1_000_000_000
+}
+
+pub(crate) fn samples_to_duration(samples: u64, sample_rate: u32) -> Duration {
+    if sample_rate == 0 {
+        return Duration::new(0, 0);
+    }
+    let sample_rate = u64::from(sample_rate);
+    Duration::new(
+        samples / sample_rate,
+        ((samples % sample_rate) * 1_000_000_000 / sample_rate) as u32,
+    )
+}
+
+/// Number of frames that must be decoded ahead of a frame to fill the
+/// Layer III bit reservoir and the overlap-add buffer
+///
+/// Preceding frames may be smaller than this one in VBR streams, so the
+/// reservoir is assumed to be spread over frames of the lowest bitrate.
+pub(crate) fn priming_frames(header: &MadHeader) -> u64 {
+    if header.layer() != Ok(MadLayer::LayerIII) || header.sample_rate == 0 {
+        // The synthesis filter only depends on the previous frame
+        return 1;
+    }
+
+    let lsf = header.flags & MAD_FLAG_LSF_EXT != 0;
+    let mono = header.mode() == Ok(MadMode::SingleChannel);
+    let (slots_per_frame, max_data_begin, side_info_len, min_bit_rate) = match (lsf, mono) {
+        (false, false) => (144, 511u64, 32, 32_000),
+        (false, true) => (144, 511, 17, 32_000),
+        (true, false) => (72, 255, 17, 8_000),
+        (true, true) => (72, 255, 9, 8_000),
+    };
+    let crc_len = if header.flags & MAD_FLAG_PROTECTION != 0 {
+        2
+    } else {
+        0
+    };
+
+    let bit_rate = if header.flags & MAD_FLAG_FREEFORMAT != 0 {
+        header.bit_rate
+    } else {
+        min_bit_rate
+    };
+    let frame_len = slots_per_frame * bit_rate / u64::from(header.sample_rate);
+    let main_data_len = max(1, frame_len.saturating_sub(4 + side_info_len + crc_len));
+
+    1 + max_data_begin.div_ceil(main_data_len)
+}
+
+fn frame_duration(header: &MadHeader) -> Duration {
+    let duration = &header.duration;
     Duration::new(
         duration.seconds as u64,
         ((duration.fraction as u64) * 1_000_000_000 / 352_800_000) as u32,
@@ -437,6 +939,18 @@ impl MadFixed32 {
     }
 }
 
+impl ops::Mul for MadFixed32 {
+    type Output = MadFixed32;
+
+    /// Fixed-point multiplication, rounding to nearest and saturating
+    fn mul(self, rhs: MadFixed32) -> MadFixed32 {
+        let product = (i64::from(self.value) * i64::from(rhs.value) + (1 << 27)) >> 28;
+        MadFixed32 {
+            value: product.clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32,
+        }
+    }
+}
+
 impl From<i16> for MadFixed32 {
     fn from(v: i16) -> MadFixed32 {
         MadFixed32 {
@@ -479,6 +993,39 @@ mod test {
     use std::path::Path;
     use std::time::Duration;
 
+    /// `count` frames of `frame_len` samples in each of `channels`
+    /// channels, taking the values of `wave` at each time in seconds
+    pub(crate) fn sine_frames(
+        sample_rate: u32,
+        frame_len: usize,
+        channels: usize,
+        count: usize,
+        wave: impl Fn(f64) -> f64,
+    ) -> Vec<Frame> {
+        (0..count)
+            .map(|n| {
+                let samples = (0..frame_len)
+                    .map(|i| {
+                        let t = (n * frame_len + i) as f64 / f64::from(sample_rate);
+                        MadFixed32::new((wave(t) * FIXED_ONE) as i32)
+                    })
+                    .collect::<Vec<_>>();
+                Frame {
+                    sample_rate,
+                    bit_rate: 0,
+                    layer: Default::default(),
+                    mode: Default::default(),
+                    samples: vec![samples; channels],
+                    duration: samples_to_duration(frame_len as u64, sample_rate),
+                    position: samples_to_duration((n * frame_len) as u64, sample_rate),
+                    offset: 0,
+                    crc_ok: None,
+                    ancillary: None,
+                }
+            })
+            .collect()
+    }
+
     #[test]
     fn test_find_duration() {
         let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
@@ -650,7 +1197,104 @@ mod test {
             }
         }
         assert_eq!(error_count, 0);
-        assert_eq!(frame_count, 77);
+        // Every frame from 3s to the end of the stream. Without priming the
+        // two frames at the seek point failed with BadDataPtr, and only 77
+        // were returned.
+        assert_eq!(frame_count, 78);
+    }
+
+    fn interleave_decode<R: io::Read>(decoder: Decoder<R>) -> Vec<Vec<i32>> {
+        let mut channels: Vec<Vec<i32>> = Vec::new();
+        for frame in decoder.filter_map(|r| r.ok()) {
+            channels.resize(frame.samples.len(), Vec::new());
+            for (out, ch) in channels.iter_mut().zip(frame.samples.iter()) {
+                out.extend(ch.iter().map(|s| s.to_raw()));
+            }
+        }
+        channels
+    }
+
+    #[test]
+    fn test_decode_interval_precise() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let file = File::open(path).unwrap();
+        let decoder = Decoder::decode_interval_precise(
+            file,
+            Duration::from_millis(1500),
+            Duration::from_millis(2500),
+        )
+        .unwrap();
+        let mut positions = Vec::new();
+        let mut sample_count = 0;
+
+        for item in decoder {
+            let f = item.unwrap();
+            positions.push(f.position);
+            sample_count += f.samples[0].len();
+            assert_eq!(f.samples.len(), 2);
+            assert_eq!(f.samples[0].len(), f.samples[1].len());
+        }
+        assert_eq!(positions[0], Duration::from_millis(1500));
+        assert_eq!(sample_count, 44100);
+
+        let file = File::open(path).unwrap();
+        let full = interleave_decode(Decoder::decode(file).unwrap());
+        let file = File::open(path).unwrap();
+        let partial = interleave_decode(
+            Decoder::decode_interval_precise(
+                file,
+                Duration::from_millis(1500),
+                Duration::from_millis(2500),
+            )
+            .unwrap(),
+        );
+        for (full_ch, partial_ch) in full.iter().zip(partial.iter()) {
+            assert_eq!(&full_ch[66150..110250], &partial_ch[..]);
+        }
+    }
+
+    #[test]
+    fn test_decode_interval_matches_linear_decode() {
+        let path = Path::new("sample_mp3s/variable_joint_stereo.mp3");
+        let file = File::open(path).unwrap();
+        let full: Vec<Frame> = Decoder::decode(file)
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+
+        for &start_ms in &[0, 300, 1_000, 2_750, 4_000] {
+            let file = File::open(path).unwrap();
+            let mut decoder = Decoder::decode_interval(
+                file,
+                Duration::from_millis(start_ms),
+                Duration::from_secs(10),
+            )
+            .unwrap();
+            let first = decoder.next().unwrap().unwrap();
+            let index = full
+                .iter()
+                .position(|f| f.position >= Duration::from_millis(start_ms))
+                .unwrap();
+
+            for (a, b) in first.samples.iter().zip(full[index].samples.iter()) {
+                let a: Vec<i32> = a.iter().map(|s| s.to_raw()).collect();
+                let b: Vec<i32> = b.iter().map(|s| s.to_raw()).collect();
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_interval_precise_from_start() {
+        let path = Path::new("sample_mp3s/constant_stereo_16.mp3");
+        let file = File::open(path).unwrap();
+        let decoder =
+            Decoder::decode_interval_precise(file, Duration::new(0, 0), Duration::from_millis(100))
+                .unwrap();
+        let partial = interleave_decode(decoder);
+
+        assert_eq!(partial.len(), 2);
+        assert_eq!(partial[0].len(), 2400);
     }
 
     #[test]
@@ -960,6 +1604,100 @@ mod test {
         assert_min_max(min_max_samples, (-1.0, 1.0));
     }
 
+    /// Two frames of MPEG-2 Layer III at 22050 Hz that libmad syncs on,
+    /// then junk, then a real stream
+    fn false_sync_stream() -> (Vec<u8>, u64) {
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            data.extend_from_slice(&[0xff, 0xf3, 0x10, 0xc0]);
+            data.extend_from_slice(&[0; 22]);
+        }
+        data.extend_from_slice(&[0x55; 100]);
+        let junk_len = data.len() as u64;
+        data.extend(std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap());
+        (data, junk_len)
+    }
+
+    #[test]
+    fn strict_sync() {
+        let (data, junk_len) = false_sync_stream();
+        let plain: Vec<_> = Decoder::decode(&data[..]).unwrap().collect();
+        assert!(plain
+            .iter()
+            .any(|r| matches!(r, Ok(f) if f.sample_rate == 22050)));
+
+        let expected: Vec<u64> = Decoder::decode(&data[junk_len as usize..])
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .map(|f| f.offset + junk_len)
+            .collect();
+        let strict: Vec<Frame> = Decoder::decode(&data[..])
+            .unwrap()
+            .with_strict_sync(3)
+            .map(|r| r.unwrap())
+            .collect();
+        assert!(strict.iter().all(|f| f.sample_rate == 44100));
+        assert_eq!(
+            strict.iter().map(|f| f.offset).collect::<Vec<_>>(),
+            expected
+        );
+
+        let headers: Vec<Frame> = Decoder::decode_headers(&data[..])
+            .unwrap()
+            .with_strict_sync(3)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(headers.len(), expected.len());
+        assert_eq!(headers[0].offset, junk_len);
+    }
+
+    #[test]
+    fn error_offsets() {
+        let (data, junk_len) = false_sync_stream();
+        let mut decoder = Decoder::decode(&data[..]).unwrap();
+        let mut errors = 0;
+        while let Some(result) = decoder.next() {
+            if let Err(SimplemadError::Mad(_)) = result {
+                assert!(decoder.frame_offset() < junk_len);
+                errors += 1;
+            }
+        }
+        assert!(errors > 0);
+    }
+
+    #[test]
+    fn strict_sync_beyond_buffer() {
+        let (data, junk_len) = false_sync_stream();
+        let expected: Vec<u64> = Decoder::decode(&data[junk_len as usize..])
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .map(|f| f.offset + junk_len)
+            .collect();
+        // The headers of early frames do not fit in the buffer
+        let strict: Vec<u64> = Decoder::decode(&data[..])
+            .unwrap()
+            .with_strict_sync(100)
+            .filter_map(|r| r.ok())
+            .map(|f| f.offset)
+            .collect();
+        assert!(strict[0] > junk_len);
+        assert!(expected.ends_with(&strict));
+    }
+
+    #[test]
+    fn strict_sync_interval() {
+        let (data, junk_len) = false_sync_stream();
+        let frames: Vec<Frame> =
+            Decoder::decode_interval(&data[..], Duration::from_secs(0), Duration::from_secs(1))
+                .unwrap()
+                .with_strict_sync(3)
+                .map(|r| r.unwrap())
+                .collect();
+        assert_eq!(frames[0].offset, junk_len);
+        assert!(frames.iter().all(|f| f.sample_rate == 44100));
+        assert_eq!(frames.len(), 39);
+    }
+
     #[allow(unused_variables)]
     #[test]
     fn test_readme_md() {
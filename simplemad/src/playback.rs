@@ -0,0 +1,342 @@
+//! Real-time playback support
+//!
+//! A `Player` runs a `Decoder` on a worker thread and keeps a lock-free
+//! ring buffer of interleaved samples topped up. An audio callback, such
+//! as one registered with cpal, drains the buffer with `Player::fill`,
+//! which never blocks or allocates.
+
+use crate::{Decoder, MadFixed32, SimplemadError};
+use std::cell::UnsafeCell;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long the worker sleeps when the ring buffer is full
+const WORKER_WAIT: Duration = Duration::from_millis(2);
+
+/// A sample type that `Player::fill` can write
+pub trait OutputSample: Copy {
+    /// The value written when no decoded audio is available
+    const SILENCE: Self;
+
+    /// Convert from libmad's fixed-point format
+    fn from_fixed(sample: MadFixed32) -> Self;
+}
+
+impl OutputSample for i16 {
+    const SILENCE: i16 = 0;
+
+    fn from_fixed(sample: MadFixed32) -> i16 {
+        sample.to_i16()
+    }
+}
+
+impl OutputSample for i32 {
+    const SILENCE: i32 = 0;
+
+    fn from_fixed(sample: MadFixed32) -> i32 {
+        sample.to_i32()
+    }
+}
+
+impl OutputSample for f32 {
+    const SILENCE: f32 = 0.0;
+
+    fn from_fixed(sample: MadFixed32) -> f32 {
+        sample.to_f32()
+    }
+}
+
+/// A single-producer, single-consumer ring buffer of raw fixed-point
+/// samples, interleaved in frames of `channels` samples
+///
+/// `written` and `read` count samples since the start and only grow, so
+/// the buffer is full when they differ by its length. Both sides move
+/// whole frames, so a read always starts on the first channel.
+struct Ring {
+    slots: Box<[UnsafeCell<i32>]>,
+    channels: usize,
+    written: AtomicU64,
+    read: AtomicU64,
+}
+
+// Each slot is accessed by one side at a time, as arbitrated by the
+// counters
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// A ring of `capacity` samples, which must be a multiple of
+    /// `channels`
+    fn new(capacity: usize, channels: usize) -> Ring {
+        Ring {
+            slots: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+            channels,
+            written: AtomicU64::new(0),
+            read: AtomicU64::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        (self.written.load(Ordering::Acquire) - self.read.load(Ordering::Acquire)) as usize
+    }
+
+    fn slot(&self, position: u64) -> &UnsafeCell<i32> {
+        &self.slots[(position % self.slots.len() as u64) as usize]
+    }
+
+    /// Round `count` down to whole frames
+    fn whole_frames(&self, count: usize) -> usize {
+        count - count % self.channels
+    }
+
+    /// Producer side: copy as many samples as fit, returning the count
+    fn push(&self, samples: &[i32]) -> usize {
+        let written = self.written.load(Ordering::Relaxed);
+        let read = self.read.load(Ordering::Acquire);
+        let free = self.slots.len() - (written - read) as usize;
+        let count = self.whole_frames(samples.len().min(free));
+
+        for (idx, sample) in samples[..count].iter().enumerate() {
+            let slot = self.slot(written + idx as u64);
+            unsafe { *slot.get() = *sample };
+        }
+
+        self.written
+            .store(written + count as u64, Ordering::Release);
+        count
+    }
+
+    /// Consumer side: fill `out` from the buffer, returning the count
+    fn pop<S: OutputSample>(&self, out: &mut [S]) -> usize {
+        let read = self.read.load(Ordering::Relaxed);
+        let written = self.written.load(Ordering::Acquire);
+        let count = self.whole_frames(out.len().min((written - read) as usize));
+
+        for (idx, sample) in out[..count].iter_mut().enumerate() {
+            let slot = self.slot(read + idx as u64);
+            *sample = S::from_fixed(MadFixed32::new(unsafe { *slot.get() }));
+        }
+
+        self.read.store(read + count as u64, Ordering::Release);
+        count
+    }
+}
+
+/// State shared between a `Player` and its worker thread
+struct Shared {
+    ring: Ring,
+    stop: AtomicBool,
+    finished: AtomicBool,
+    sample_rate: AtomicU32,
+}
+
+/// Decodes on a background thread for playback from an audio callback
+///
+/// Samples are interleaved with a fixed channel count chosen when the
+/// player is spawned. Mono streams are copied to every output channel and
+/// extra stream channels are dropped. Sample rates are passed through
+/// unchanged; check `sample_rate` against the output device.
+pub struct Player {
+    shared: Arc<Shared>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Player {
+    /// Start decoding on a worker thread into a ring buffer that holds
+    /// `capacity` samples across all `channels`, rounded down to whole
+    /// frames
+    pub fn spawn<R>(decoder: Decoder<R>, channels: u16, capacity: usize) -> Player
+    where
+        R: io::Read + Send + 'static,
+    {
+        let channels = usize::from(channels.max(1));
+        let shared = Arc::new(Shared {
+            ring: Ring::new(capacity.max(channels) / channels * channels, channels),
+            stop: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            sample_rate: AtomicU32::new(0),
+        });
+
+        let worker_shared = shared.clone();
+        let worker = thread::spawn(move || {
+            decode_into(decoder, channels, &worker_shared);
+            worker_shared.finished.store(true, Ordering::Release);
+        });
+
+        Player {
+            shared,
+            worker: Some(worker),
+        }
+    }
+
+    /// Fill `out` with interleaved samples, padding with silence if the
+    /// worker has fallen behind or finished
+    ///
+    /// Only whole frames are taken from the buffer, so the next call starts
+    /// on the first channel even if `out` ends partway through a frame.
+    ///
+    /// Returns the number of decoded samples written. This never blocks or
+    /// allocates, so it is safe to call from an audio callback.
+    pub fn fill<S: OutputSample>(&self, out: &mut [S]) -> usize {
+        let count = self.shared.ring.pop(out);
+        for sample in &mut out[count..] {
+            *sample = S::SILENCE;
+        }
+        count
+    }
+
+    /// Number of decoded samples waiting in the ring buffer
+    pub fn buffered(&self) -> usize {
+        self.shared.ring.len()
+    }
+
+    /// Sample rate of the most recently decoded frame, or `None` before the
+    /// first frame is decoded
+    pub fn sample_rate(&self) -> Option<u32> {
+        match self.shared.sample_rate.load(Ordering::Acquire) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Whether the stream has ended and every sample has been played
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire) && self.shared.ring.len() == 0
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn decode_into<R: io::Read>(decoder: Decoder<R>, channels: usize, shared: &Shared) {
+    let mut interleaved = Vec::new();
+
+    for result in decoder {
+        let frame = match result {
+            Ok(frame) => frame,
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(_) => return,
+        };
+        if frame.samples.is_empty() {
+            continue;
+        }
+        shared
+            .sample_rate
+            .store(frame.sample_rate, Ordering::Release);
+
+        interleaved.clear();
+        for idx in 0..frame.samples[0].len() {
+            for ch in 0..channels {
+                let source = frame.samples.get(ch).unwrap_or(&frame.samples[0]);
+                interleaved.push(source[idx].to_raw());
+            }
+        }
+
+        let mut pushed = 0;
+        while pushed < interleaved.len() {
+            if shared.stop.load(Ordering::Acquire) {
+                return;
+            }
+            pushed += shared.ring.push(&interleaved[pushed..]);
+            if pushed < interleaved.len() {
+                thread::park_timeout(WORKER_WAIT);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::path::Path;
+
+    fn drain<S: OutputSample>(player: &Player) -> Vec<S> {
+        let mut output = Vec::new();
+        let mut block = [S::SILENCE; 512];
+        while !player.is_finished() {
+            let count = player.fill(&mut block);
+            output.extend_from_slice(&block[..count]);
+            if count == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn drains_whole_stream() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let decoder = Decoder::decode(File::open(path).unwrap()).unwrap();
+        let expected: Vec<i16> = Decoder::decode(File::open(path).unwrap())
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .flat_map(|f| {
+                (0..f.samples[0].len())
+                    .flat_map(|i| vec![f.samples[0][i].to_i16(), f.samples[1][i].to_i16()])
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let player = Player::spawn(decoder, 2, 4096);
+        let output: Vec<i16> = drain(&player);
+
+        assert_eq!(player.sample_rate(), Some(44100));
+        assert_eq!(output.len(), 193 * 1152 * 2);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn mono_to_stereo_f32() {
+        let path = Path::new("sample_mp3s/constant_single_channel_128.mp3");
+        let decoder = Decoder::decode(File::open(path).unwrap()).unwrap();
+        let player = Player::spawn(decoder, 2, 1000);
+        let output: Vec<f32> = drain(&player);
+
+        assert_eq!(output.len(), 193 * 1152 * 2);
+        assert!(output.chunks(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn underrun_keeps_channels_aligned() {
+        // Left samples are positive and right samples their negation
+        let source: Vec<i32> = (1..=50).flat_map(|n| vec![n << 16, -(n << 16)]).collect();
+        let ring = Ring::new(6, 2);
+        let mut output = Vec::new();
+        let mut pushed = 0;
+        while output.len() < source.len() {
+            // Push less than is read, so that every read runs dry
+            let end = (pushed + 3).min(source.len());
+            pushed += ring.push(&source[pushed..end]);
+            let mut block = [1.0f32; 5];
+            let count = ring.pop(&mut block);
+            assert_eq!(count % 2, 0);
+            output.extend_from_slice(&block[..count]);
+        }
+
+        assert_eq!(output.len(), source.len());
+        assert!(output
+            .chunks(2)
+            .all(|pair| pair[0] == -pair[1] && pair[0] > 0.0));
+    }
+
+    #[test]
+    fn fill_pads_with_silence() {
+        let player = Player::spawn(Decoder::decode(&[][..]).unwrap(), 2, 64);
+        let mut block = [1.0f32; 16];
+        while !player.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(player.fill(&mut block), 0);
+        assert!(block.iter().all(|&s| s == 0.0));
+    }
+}
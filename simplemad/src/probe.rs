@@ -0,0 +1,519 @@
+//! Stream summaries gathered without decoding any audio
+
+use crate::xing::{parse_vbri, parse_xing, VbriHeader, XingHeader};
+use crate::{frame_sample_count, samples_to_duration, Decoder, SimplemadError};
+use simplemad_sys::*;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// Number of bytes kept from the end of the stream to find trailing tags
+pub(crate) const TAIL_LEN: usize = 128 + 32;
+
+/// MPEG audio version
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MpegVersion {
+    /// MPEG-1
+    Mpeg1,
+    /// MPEG-2 (lower sampling frequencies)
+    Mpeg2,
+    /// MPEG-2.5 (unofficial extension)
+    Mpeg25,
+}
+
+impl MpegVersion {
+    pub(crate) fn from_header(header: &MadHeader) -> MpegVersion {
+        if header.flags & MAD_FLAG_MPEG_2_5_EXT != 0 {
+            MpegVersion::Mpeg25
+        } else if header.flags & MAD_FLAG_LSF_EXT != 0 {
+            MpegVersion::Mpeg2
+        } else {
+            MpegVersion::Mpeg1
+        }
+    }
+}
+
+/// How the bit rate varies over a stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitRateMode {
+    /// Every frame has the same bit rate
+    Constant,
+    /// The bit rate varies around a target average
+    Average,
+    /// The bit rate varies freely
+    Variable,
+}
+
+/// The source of the duration and frame count in a `StreamInfo`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InfoSource {
+    /// A Xing or Info tag in the first frame
+    Xing,
+    /// A VBRI tag in the first frame
+    Vbri,
+    /// A scan of every frame header
+    Scan,
+}
+
+/// Bit rate statistics of a stream
+#[derive(Clone, Debug)]
+pub struct BitRateStats {
+    /// Average bit rate in bits per second
+    pub average: u32,
+    /// Lowest frame bit rate, if every frame was scanned
+    pub min: Option<u32>,
+    /// Highest frame bit rate, if every frame was scanned
+    pub max: Option<u32>,
+    /// Number of frames at each bit rate, if every frame was scanned
+    pub histogram: BTreeMap<u32, u64>,
+}
+
+/// A summary of an MPEG audio stream
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    /// Total duration of the audio
+    pub duration: Duration,
+    /// Number of audio frames, not counting a Xing or VBRI tag frame
+    pub frame_count: u64,
+    /// Bit rate statistics
+    pub bit_rate: BitRateStats,
+    /// Constant, average or variable bit rate
+    pub bit_rate_mode: BitRateMode,
+    /// Number of samples per second
+    pub sample_rate: u32,
+    /// Single Channel, Dual Channel, Joint Stereo or Stereo
+    pub mode: MadMode,
+    /// MPEG version
+    pub version: MpegVersion,
+    /// Audio layer (I, II or III)
+    pub layer: MadLayer,
+    /// Byte offset of the first audio frame
+    pub audio_offset: u64,
+    /// Size of the ID3v2 tag at the start of the stream
+    pub id3v2_size: u64,
+    /// Size of the APEv2 tag at the end of the stream
+    pub ape_size: u64,
+    /// Size of the ID3v1 tag at the end of the stream
+    pub id3v1_size: u64,
+    /// Total size of the stream in bytes
+    pub stream_size: u64,
+    /// Where the duration and frame count came from
+    pub source: InfoSource,
+}
+
+/// Summarise a stream using its Xing or VBRI tag when present, or a scan
+/// of every frame header otherwise
+///
+/// The whole stream is read in either case, to find any trailing tags.
+/// Returns `SimplemadError::EOF` if the stream contains no MPEG audio.
+pub fn probe<R: io::Read>(reader: R) -> Result<StreamInfo, SimplemadError> {
+    let mut reader = TailReader::new(reader);
+
+    let mut head = [0u8; 10];
+    let (id3v2_size, head) = skip_id3v2(&mut reader, &mut head)?;
+
+    let mut scan = Scan::default();
+    let mut first: Option<FirstFrame> = None;
+    {
+        let mut decoder = Decoder::decode_headers(head.chain(&mut reader))?;
+        while let Some(result) = decoder.next() {
+            let frame = match result {
+                Ok(frame) => frame,
+                Err(SimplemadError::Mad(_)) => continue,
+                Err(e) => return Err(e),
+            };
+
+            if first.is_none() {
+                let header = *decoder.header();
+                let bytes = decoder.frame_bytes();
+                let xing = parse_xing(bytes, &header);
+                let vbri = parse_vbri(bytes);
+                let tagged = xing.is_some() || vbri.is_some();
+                let audio_offset = if tagged {
+                    frame.offset + bytes.len() as u64
+                } else {
+                    frame.offset
+                };
+                let info = FirstFrame {
+                    header,
+                    len: bytes.len() as u64,
+                    audio_offset: id3v2_size + audio_offset,
+                    xing,
+                    vbri,
+                };
+                let complete = info.frame_count().is_some();
+                first = Some(info);
+                if complete {
+                    break;
+                }
+                if tagged {
+                    continue;
+                }
+            }
+
+            scan.add(frame.bit_rate, frame.duration);
+        }
+    }
+
+    io::copy(&mut reader, &mut io::sink())?;
+
+    let first = first.ok_or(SimplemadError::EOF)?;
+    let header = &first.header;
+    let (ape_size, id3v1_size) = reader.trailing_tag_sizes();
+    let audio_size = reader
+        .total
+        .saturating_sub(first.audio_offset + ape_size + id3v1_size);
+
+    let (source, frame_count, duration, bit_rate) = match first.frame_count() {
+        Some(frame_count) => {
+            let duration =
+                samples_to_duration(frame_count * frame_sample_count(header), header.sample_rate);
+            let tagged_size = first
+                .xing
+                .as_ref()
+                .and_then(|x| x.bytes)
+                .or_else(|| first.vbri.map(|v| v.bytes))
+                .map(|b| u64::from(b).saturating_sub(first.len));
+            let bytes = tagged_size.unwrap_or(audio_size);
+            let average = if duration > Duration::new(0, 0) {
+                (bytes as f64 * 8.0 / duration.as_secs_f64()).round() as u32
+            } else {
+                0
+            };
+            let source = if first.xing.is_some() {
+                InfoSource::Xing
+            } else {
+                InfoSource::Vbri
+            };
+            let bit_rate = BitRateStats {
+                average,
+                min: None,
+                max: None,
+                histogram: BTreeMap::new(),
+            };
+            (source, frame_count, duration, bit_rate)
+        }
+        None => {
+            if scan.frame_count == 0 {
+                return Err(SimplemadError::EOF);
+            }
+            let bit_rate = BitRateStats {
+                average: (scan.bit_rate_total / scan.frame_count) as u32,
+                min: scan.histogram.keys().next().cloned(),
+                max: scan.histogram.keys().next_back().cloned(),
+                histogram: scan.histogram,
+            };
+            (InfoSource::Scan, scan.frame_count, scan.duration, bit_rate)
+        }
+    };
+
+    let bit_rate_mode = match (&first.xing, &first.vbri) {
+        (Some(xing), _) => match xing.lame {
+            Some(lame) if lame.is_abr() => BitRateMode::Average,
+            Some(lame) if lame.is_cbr() => BitRateMode::Constant,
+            _ if !xing.vbr => BitRateMode::Constant,
+            _ => BitRateMode::Variable,
+        },
+        (None, Some(_)) => BitRateMode::Variable,
+        (None, None) if bit_rate.min == bit_rate.max => BitRateMode::Constant,
+        (None, None) => BitRateMode::Variable,
+    };
+
+    Ok(StreamInfo {
+        duration,
+        frame_count,
+        bit_rate,
+        bit_rate_mode,
+        sample_rate: header.sample_rate,
+        mode: header.mode().map_err(SimplemadError::Unknown)?,
+        version: MpegVersion::from_header(header),
+        layer: header.layer().map_err(SimplemadError::Unknown)?,
+        audio_offset: first.audio_offset,
+        id3v2_size,
+        ape_size,
+        id3v1_size,
+        stream_size: reader.total,
+        source,
+    })
+}
+
+#[cfg(any(feature = "rodio", test))]
+/// The duration given by a Xing or VBRI tag in the first frame, or `None`
+/// if there is no such tag, reading little more than the first frame
+pub(crate) fn tagged_duration<R: io::Read>(
+    mut reader: R,
+) -> Result<Option<Duration>, SimplemadError> {
+    let mut head = [0u8; 10];
+    let (_, head) = skip_id3v2(&mut reader, &mut head)?;
+
+    let mut decoder = Decoder::decode_headers(head.chain(reader))?;
+    while let Some(result) = decoder.next() {
+        match result {
+            Ok(_) => {}
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(SimplemadError::EOF) => break,
+            Err(e) => return Err(e),
+        }
+        let header = *decoder.header();
+        let bytes = decoder.frame_bytes();
+        let frame_count = tagged_frame_count(&parse_xing(bytes, &header), &parse_vbri(bytes));
+        return Ok(frame_count.map(|frame_count| {
+            samples_to_duration(
+                frame_count * frame_sample_count(&header),
+                header.sample_rate,
+            )
+        }));
+    }
+    Ok(None)
+}
+
+/// Skip any ID3v2 tag at the start of `reader`, returning its size and the
+/// bytes of `head` read past it
+fn skip_id3v2<'a, R: io::Read>(
+    reader: &mut R,
+    head: &'a mut [u8; 10],
+) -> io::Result<(u64, &'a [u8])> {
+    let head_len = read_fully(reader, head)?;
+    let id3v2_size = id3v2_size(&head[..head_len]);
+    if id3v2_size > 0 {
+        io::copy(
+            &mut reader.take(id3v2_size - head_len as u64),
+            &mut io::sink(),
+        )?;
+        Ok((id3v2_size, &head[..0]))
+    } else {
+        Ok((0, &head[..head_len]))
+    }
+}
+
+/// Details of the first frame found in a stream
+struct FirstFrame {
+    header: MadHeader,
+    len: u64,
+    audio_offset: u64,
+    xing: Option<XingHeader>,
+    vbri: Option<VbriHeader>,
+}
+
+impl FirstFrame {
+    fn frame_count(&self) -> Option<u64> {
+        tagged_frame_count(&self.xing, &self.vbri)
+    }
+}
+
+/// The frame count stored in a Xing or VBRI tag
+fn tagged_frame_count(xing: &Option<XingHeader>, vbri: &Option<VbriHeader>) -> Option<u64> {
+    match (xing, vbri) {
+        (Some(xing), _) => xing.frames.map(u64::from),
+        (None, Some(vbri)) => Some(u64::from(vbri.frames)),
+        (None, None) => None,
+    }
+}
+
+/// Running totals of a frame header scan
+#[derive(Default)]
+struct Scan {
+    frame_count: u64,
+    bit_rate_total: u64,
+    duration: Duration,
+    histogram: BTreeMap<u32, u64>,
+}
+
+impl Scan {
+    fn add(&mut self, bit_rate: u32, duration: Duration) {
+        self.frame_count += 1;
+        self.bit_rate_total += u64::from(bit_rate);
+        self.duration += duration;
+        *self.histogram.entry(bit_rate).or_insert(0) += 1;
+    }
+}
+
+/// A reader that counts the bytes passing through it and keeps the last
+/// few of them
+struct TailReader<R> {
+    inner: R,
+    total: u64,
+    tail: Vec<u8>,
+}
+
+impl<R: io::Read> TailReader<R> {
+    fn new(inner: R) -> TailReader<R> {
+        TailReader {
+            inner,
+            total: 0,
+            tail: Vec::with_capacity(TAIL_LEN * 2),
+        }
+    }
+
+    /// Sizes of the APEv2 and ID3v1 tags at the end of the stream
+    fn trailing_tag_sizes(&self) -> (u64, u64) {
+        trailing_tag_sizes(&self.tail)
+    }
+}
+
+impl<R: io::Read> io::Read for TailReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.total += n as u64;
+        self.tail.extend_from_slice(&buf[..n]);
+        if self.tail.len() > TAIL_LEN {
+            let excess = self.tail.len() - TAIL_LEN;
+            self.tail.drain(..excess);
+        }
+        Ok(n)
+    }
+}
+
+/// Sizes of the APEv2 and ID3v1 tags at the end of `tail`, the last bytes
+/// of a stream
+pub(crate) fn trailing_tag_sizes(tail: &[u8]) -> (u64, u64) {
+    let id3v1_size = if tail.len() >= 128 && &tail[tail.len() - 128..][..3] == b"TAG" {
+        128
+    } else {
+        0
+    };
+
+    let footer_end = tail.len() - id3v1_size as usize;
+    let ape_size = match footer_end
+        .checked_sub(32)
+        .map(|start| &tail[start..footer_end])
+    {
+        Some(footer) if &footer[..8] == b"APETAGEX" => {
+            let size = u64::from(read_u32_le(&footer[12..16]));
+            let flags = read_u32_le(&footer[20..24]);
+            let header_size = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+            size + header_size
+        }
+        _ => 0,
+    };
+
+    (ape_size, id3v1_size)
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0])
+        | u32::from(bytes[1]) << 8
+        | u32::from(bytes[2]) << 16
+        | u32::from(bytes[3]) << 24
+}
+
+/// Read until `buf` is full or the reader is exhausted
+pub(crate) fn read_fully<R: io::Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Size of the ID3v2 tag that begins with `head`, including its header
+/// and footer
+pub(crate) fn id3v2_size(head: &[u8]) -> u64 {
+    if head.len() < 10 || &head[..3] != b"ID3" {
+        return 0;
+    }
+
+    let size = head[6..10]
+        .iter()
+        .fold(0u64, |acc, &b| (acc << 7) | u64::from(b & 0x7f));
+    let footer_size = if head[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer_size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn probe_constant_stereo_128() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let info = probe(File::open(path).unwrap()).unwrap();
+
+        assert_eq!(info.source, InfoSource::Xing);
+        assert_eq!(info.bit_rate_mode, BitRateMode::Constant);
+        assert_eq!(info.frame_count, 193);
+        assert_eq!(info.duration, Duration::new(5, 41632653));
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.mode, MadMode::Stereo);
+        assert_eq!(info.version, MpegVersion::Mpeg1);
+        assert_eq!(info.layer, MadLayer::LayerIII);
+        assert_eq!(info.audio_offset, 417);
+        assert_eq!(info.stream_size, 81083);
+        assert!((info.bit_rate.average as i64 - 128_000).abs() < 1000);
+    }
+
+    #[test]
+    fn probe_variable_and_average() {
+        let path = Path::new("sample_mp3s/variable_stereo.mp3");
+        let info = probe(File::open(path).unwrap()).unwrap();
+        assert_eq!(info.source, InfoSource::Xing);
+        assert_eq!(info.bit_rate_mode, BitRateMode::Variable);
+
+        let path = Path::new("sample_mp3s/average_stereo_128.mp3");
+        let info = probe(File::open(path).unwrap()).unwrap();
+        assert_eq!(info.source, InfoSource::Xing);
+        assert_eq!(info.bit_rate_mode, BitRateMode::Average);
+    }
+
+    #[test]
+    fn probe_scan() {
+        let path = Path::new("sample_mp3s/constant_stereo_16.mp3");
+        let info = probe(File::open(path).unwrap()).unwrap();
+
+        assert_eq!(info.source, InfoSource::Scan);
+        assert_eq!(info.bit_rate_mode, BitRateMode::Constant);
+        assert_eq!(info.frame_count, 210);
+        assert_eq!(info.sample_rate, 24000);
+        assert_eq!(info.version, MpegVersion::Mpeg2);
+        assert_eq!(info.bit_rate.min, Some(16000));
+        assert_eq!(info.bit_rate.max, Some(16000));
+        assert_eq!(info.bit_rate.histogram.get(&16000), Some(&210));
+        assert_eq!(info.audio_offset, 0);
+    }
+
+    #[test]
+    fn probe_trailing_id3v1() {
+        let path = Path::new("sample_mp3s/rodiomusic.mp3");
+        let info = probe(File::open(path).unwrap()).unwrap();
+
+        assert_eq!(info.source, InfoSource::Scan);
+        assert_eq!(info.id3v1_size, 128);
+        assert_eq!(info.id3v2_size, 0);
+    }
+
+    #[test]
+    fn probe_leading_id3v2() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+        data.extend([0xff; 128].iter());
+        let mut file = File::open(path).unwrap();
+        file.read_to_end(&mut data).unwrap();
+
+        let info = probe(&data[..]).unwrap();
+        assert_eq!(info.id3v2_size, 138);
+        assert_eq!(info.audio_offset, 138 + 417);
+        assert_eq!(info.frame_count, 193);
+
+        assert_eq!(tagged_duration(&data[..]).unwrap(), Some(info.duration));
+    }
+
+    #[test]
+    fn tagged_duration_needs_tag() {
+        let path = Path::new("sample_mp3s/constant_stereo_16.mp3");
+        assert_eq!(tagged_duration(File::open(path).unwrap()).unwrap(), None);
+        assert_eq!(tagged_duration(&[][..]).unwrap(), None);
+    }
+
+    #[test]
+    fn probe_empty() {
+        match probe(&[][..]) {
+            Err(SimplemadError::EOF) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|i| i.frame_count)),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Safe wrappers around libmad's decoding stages, for callers that manage
+//! their own buffering or want to time each stage
+//!
+//! A `Stream` reads frames from a borrowed buffer, a `FrameDecoder` decodes
+//! headers and subband samples from it, and a `Synth` turns those into PCM.
+//! `Decoder` is built from the same pieces.
+//!
+//! ```no_run
+//! use simplemad::raw::{FrameDecoder, Stream, Synth};
+//!
+//! let data = std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap();
+//! let mut stream = Stream::new();
+//! let mut frame = FrameDecoder::new();
+//! let mut synth = Synth::new();
+//! stream.set_buffer(&data);
+//!
+//! while frame.decode(&mut stream).is_ok() {
+//!     let pcm = synth.synthesize(&frame);
+//!     println!("{} samples at {} Hz", pcm.length, pcm.sample_rate);
+//! }
+//! ```
+
+use crate::{AncillaryData, SimplemadError};
+use simplemad_sys::*;
+use std::cmp::min;
+use std::marker::PhantomData;
+
+/// The input bitstream, reading from a buffer borrowed for `'a`
+pub struct Stream<'a> {
+    stream: MadStream,
+    data: PhantomData<&'a [u8]>,
+}
+
+impl<'a> Stream<'a> {
+    /// Create a stream with no input
+    pub fn new() -> Stream<'a> {
+        let mut stream = MadStream::default();
+        unsafe {
+            mad_stream_init(&mut stream);
+        }
+        Stream {
+            stream,
+            data: PhantomData,
+        }
+    }
+
+    /// Read from `buffer`, starting at its first byte
+    ///
+    /// A frame that runs past the end of the buffer fails with
+    /// `MadError::BufLen`. To continue, pass a buffer starting with the
+    /// bytes from `next_frame` onwards. The last frame of a stream decodes
+    /// only when at least 8 zero bytes follow it.
+    pub fn set_buffer(&mut self, buffer: &'a [u8]) {
+        unsafe { self.set_buffer_ptr(buffer.as_ptr(), buffer.len()) }
+    }
+
+    /// Read from `len` bytes at `buffer`, as for `set_buffer`
+    ///
+    /// # Safety
+    ///
+    /// The bytes must stay valid and unchanged until the stream is given
+    /// another buffer, reset or dropped.
+    pub unsafe fn set_buffer_ptr(&mut self, buffer: *const u8, len: usize) {
+        mad_stream_buffer(&mut self.stream, buffer, len as c_ulong);
+    }
+
+    /// Number of bytes in the current buffer
+    pub fn buffer_len(&self) -> usize {
+        self.stream.buff_end as usize - self.stream.buffer as usize
+    }
+
+    /// Offset in the buffer of the frame most recently decoded, or being
+    /// decoded when an error occurred
+    pub fn this_frame(&self) -> usize {
+        self.stream.this_frame as usize - self.stream.buffer as usize
+    }
+
+    /// Offset in the buffer where decoding continues, which may be past its
+    /// end after a `MadError::BufLen`
+    pub fn next_frame(&self) -> usize {
+        self.stream.next_frame as usize - self.stream.buffer as usize
+    }
+
+    /// Continue from `position` in the buffer, searching for a sync word
+    /// from there rather than expecting a frame
+    pub fn resync(&mut self, position: usize) {
+        let position = min(position, self.buffer_len());
+        self.stream.next_frame = self.stream.buffer.wrapping_add(position);
+        self.stream.sync = 0;
+    }
+
+    /// The bytes of the frame most recently decoded
+    pub fn frame_bytes(&self) -> &[u8] {
+        let len = self.next_frame().saturating_sub(self.this_frame());
+        if len == 0 || self.next_frame() > self.buffer_len() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.stream.this_frame as *const u8, len) }
+    }
+
+    /// The ancillary bits of the frame most recently decoded, which are
+    /// only meaningful after a successful `FrameDecoder::decode`
+    pub fn ancillary_data(&self) -> AncillaryData {
+        unsafe { AncillaryData::read(&self.stream.anc_ptr, self.stream.anc_bitlen as usize) }
+    }
+
+    /// The `MAD_OPTION_*` flags in use
+    pub fn options(&self) -> i32 {
+        self.stream.options
+    }
+
+    /// Set the `MAD_OPTION_*` flags, which take effect from the next frame
+    pub fn set_options(&mut self, options: i32) {
+        self.stream.options = options;
+    }
+
+    /// Drop the buffer and any Layer III bit reservoir, as after a seek,
+    /// keeping the options
+    pub fn reset(&mut self) {
+        let options = self.stream.options;
+        unsafe {
+            mad_stream_finish(&mut self.stream);
+            mad_stream_init(&mut self.stream);
+        }
+        self.stream.options = options;
+    }
+
+    /// Take the error left by the last operation, clearing it
+    fn take_error(&mut self) -> Result<(), SimplemadError> {
+        let error = match self.stream.error() {
+            Ok(MadError::None) => return Ok(()),
+            Ok(error) => SimplemadError::Mad(error),
+            Err(code) => SimplemadError::Unknown(code),
+        };
+        self.stream.error = MadError::None as i32;
+        Err(error)
+    }
+}
+
+impl Default for Stream<'_> {
+    fn default() -> Self {
+        Stream::new()
+    }
+}
+
+impl Drop for Stream<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            mad_stream_finish(&mut self.stream);
+        }
+    }
+}
+
+// The stream only points into the borrowed buffer and into memory that
+// libmad allocates for it
+unsafe impl Send for Stream<'_> {}
+
+/// Decodes frame headers and subband samples from a `Stream`
+pub struct FrameDecoder {
+    frame: MadFrame,
+}
+
+impl FrameDecoder {
+    /// Create a frame decoder
+    pub fn new() -> FrameDecoder {
+        let mut frame = MadFrame::default();
+        unsafe {
+            mad_frame_init(&mut frame);
+        }
+        FrameDecoder { frame }
+    }
+
+    /// Decode the header of the next frame
+    ///
+    /// The following `decode` finishes this frame, and `decode_header`
+    /// skips it.
+    pub fn decode_header(&mut self, stream: &mut Stream) -> Result<&MadHeader, SimplemadError> {
+        self.discard_header();
+        unsafe {
+            mad_header_decode(&mut self.frame.header, &mut stream.stream);
+        }
+        stream.take_error()?;
+        Ok(&self.frame.header)
+    }
+
+    /// Decode the next frame, or finish the one whose header was decoded
+    /// by `decode_header`
+    pub fn decode(&mut self, stream: &mut Stream) -> Result<&MadHeader, SimplemadError> {
+        unsafe {
+            mad_frame_decode(&mut self.frame, &mut stream.stream);
+        }
+        stream.take_error()?;
+        Ok(&self.frame.header)
+    }
+
+    /// Forget a header decoded by `decode_header`, so that the next
+    /// `decode` reads a new one
+    pub fn discard_header(&mut self) {
+        self.frame.header.flags &= !MAD_FLAG_INCOMPLETE;
+    }
+
+    /// The header most recently decoded, which is the one a failed decode
+    /// was working on for errors past the header stage
+    pub fn header(&self) -> &MadHeader {
+        &self.frame.header
+    }
+
+    /// Silence the subband samples and the overlap with the next frame
+    pub fn mute(&mut self) {
+        unsafe {
+            mad_frame_mute(&mut self.frame);
+        }
+        self.discard_header();
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl Drop for FrameDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            mad_frame_finish(&mut self.frame);
+        }
+    }
+}
+
+// The overlap buffer is allocated by libmad for this frame alone
+unsafe impl Send for FrameDecoder {}
+
+/// Synthesizes PCM from decoded frames
+pub struct Synth {
+    synth: MadSynth,
+}
+
+impl Synth {
+    /// Create a synthesis filter
+    pub fn new() -> Synth {
+        let mut synth = MadSynth::default();
+        unsafe {
+            mad_synth_init(&mut synth);
+        }
+        Synth { synth }
+    }
+
+    /// Synthesize the frame most recently decoded by `frame`
+    pub fn synthesize(&mut self, frame: &FrameDecoder) -> &MadPcm {
+        unsafe {
+            mad_synth_frame(&mut self.synth, &frame.frame);
+        }
+        &self.synth.pcm
+    }
+
+    /// The PCM most recently synthesized
+    pub fn pcm(&self) -> &MadPcm {
+        &self.synth.pcm
+    }
+
+    /// Silence the filter state carried between frames
+    pub fn mute(&mut self) {
+        unsafe {
+            mad_synth_mute(&mut self.synth);
+        }
+    }
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        Synth::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::BUFFER_GUARD;
+
+    fn sample() -> Vec<u8> {
+        let mut data = std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap();
+        data.extend_from_slice(&[0; BUFFER_GUARD]);
+        data
+    }
+
+    #[test]
+    fn decodes_all_frames() {
+        let data = sample();
+        let mut stream = Stream::new();
+        let mut frame = FrameDecoder::new();
+        let mut synth = Synth::new();
+        stream.set_buffer(&data);
+
+        let mut frames = 0;
+        loop {
+            match frame.decode(&mut stream) {
+                Ok(header) => assert_eq!(header.sample_rate, 44100),
+                Err(SimplemadError::Mad(MadError::BufLen)) => break,
+                Err(SimplemadError::Mad(_)) => continue,
+                Err(e) => panic!("{:?}", e),
+            }
+            assert_eq!(
+                stream.frame_bytes().len(),
+                stream.next_frame() - stream.this_frame()
+            );
+            let pcm = synth.synthesize(&frame);
+            assert_eq!((pcm.channels, pcm.length), (2, 1152));
+            frames += 1;
+        }
+        // The guard lets the last frame decode
+        assert_eq!(frames, 194);
+    }
+
+    #[test]
+    fn header_then_frame() {
+        let data = sample();
+        let mut stream = Stream::new();
+        let mut frame = FrameDecoder::new();
+        stream.set_buffer(&data);
+
+        let offset = loop {
+            if frame.decode_header(&mut stream).is_ok() {
+                break stream.this_frame();
+            }
+        };
+        // The pending header is reused rather than decoded again
+        frame.decode(&mut stream).unwrap();
+        assert_eq!(stream.this_frame(), offset);
+
+        // Discarding it moves on to the next frame
+        frame.decode_header(&mut stream).unwrap();
+        let skipped = stream.this_frame();
+        assert!(skipped > offset);
+        frame.discard_header();
+        let _ = frame.decode(&mut stream);
+        assert!(stream.this_frame() > skipped);
+    }
+
+    #[test]
+    fn errors_and_reset() {
+        let junk = [0x55u8; 64];
+        let data = sample();
+        let mut stream = Stream::new();
+        let mut frame = FrameDecoder::new();
+        stream.set_options(MAD_OPTION_IGNORECRC);
+
+        stream.set_buffer(&junk);
+        assert!(matches!(
+            frame.decode(&mut stream),
+            Err(SimplemadError::Mad(MadError::LostSync))
+                | Err(SimplemadError::Mad(MadError::BufLen))
+        ));
+
+        stream.reset();
+        assert_eq!(stream.options(), MAD_OPTION_IGNORECRC);
+        assert_eq!(stream.buffer_len(), 0);
+
+        stream.set_buffer(&data);
+        while frame.decode(&mut stream).is_err() {}
+        assert_eq!(frame.header().layer(), Ok(MadLayer::LayerIII));
+    }
+}
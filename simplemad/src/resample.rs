@@ -0,0 +1,435 @@
+//! Sample rate conversion of decoded frames to a fixed output rate
+
+use crate::{samples_to_duration, Frame, MadFixed32, SimplemadError};
+use std::f64::consts::PI;
+
+/// Trade-off between the speed and the accuracy of a `Resampler`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    /// Short filters, for previews and voice
+    Fast,
+    /// Good enough for most music playback
+    Medium,
+    /// Long filters with a steep cutoff, for mastering or analysis
+    High,
+}
+
+impl Quality {
+    /// Zero crossings of the sinc on each side of the filter's center
+    fn zero_crossings(self) -> usize {
+        match self {
+            Quality::Fast => 8,
+            Quality::Medium => 16,
+            Quality::High => 32,
+        }
+    }
+
+    /// Filter table entries per zero crossing
+    fn phases(self) -> usize {
+        match self {
+            Quality::Fast => 64,
+            Quality::Medium => 256,
+            Quality::High => 1024,
+        }
+    }
+
+    /// Kaiser window shape, trading stopband attenuation against
+    /// transition width
+    fn beta(self) -> f64 {
+        match self {
+            Quality::Fast => 6.0,
+            Quality::Medium => 8.0,
+            Quality::High => 10.0,
+        }
+    }
+
+    /// Fraction of the lower Nyquist frequency that passes unattenuated
+    fn rolloff(self) -> f64 {
+        match self {
+            Quality::Fast => 0.85,
+            Quality::Medium => 0.92,
+            Quality::High => 0.96,
+        }
+    }
+}
+
+/// Modified Bessel function of the first kind, order zero
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// One side of a Kaiser windowed sinc, sampled `phases` times per zero
+/// crossing, with a trailing zero to interpolate towards
+fn filter_table(quality: Quality) -> Vec<f64> {
+    let zero_crossings = quality.zero_crossings();
+    let len = zero_crossings * quality.phases();
+    let beta = quality.beta();
+
+    let mut table: Vec<f64> = (0..len)
+        .map(|n| {
+            let x = n as f64 / quality.phases() as f64;
+            let sinc = if n == 0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let r = x / zero_crossings as f64;
+            sinc * bessel_i0(beta * (1.0 - r * r).sqrt()) / bessel_i0(beta)
+        })
+        .collect();
+    table.push(0.0);
+    table
+}
+
+/// Input samples at one sample rate, and the position of the next output
+/// sample among them
+struct Segment {
+    input_rate: u32,
+    /// Input samples per output sample
+    step: f64,
+    /// Cutoff as a fraction of the input Nyquist frequency
+    scale: f64,
+    /// Input samples on each side of an output sample that contribute to it
+    half_width: f64,
+    /// Input samples for each channel, starting with those no longer
+    /// needed after the next output sample
+    history: Vec<Vec<f64>>,
+    /// Position of the next output sample in `history`
+    time: f64,
+}
+
+/// Converts the frames of a `Decoder`, or any iterator of decoding results,
+/// to a fixed sample rate
+///
+/// A band-limited sinc interpolator is evaluated at each output sample,
+/// with its cutoff lowered below the output Nyquist frequency when
+/// downsampling. When the input sample rate or channel count changes the
+/// old rate is played out to its last sample and the new one picks up
+/// without a gap, holding the last sample across the join. Samples that
+/// already have the target rate are copied unchanged.
+///
+/// Each output frame holds the samples that became available with the
+/// corresponding input frame, so the first is a little short and a final
+/// frame holds the remainder. Errors are passed on as they are.
+pub struct Resampler<I> {
+    inner: I,
+    target_rate: u32,
+    quality: Quality,
+    table: Vec<f64>,
+    segment: Option<Segment>,
+    /// The most recent frame with samples, minus its samples, to describe
+    /// the final output frame
+    last_frame: Option<Frame>,
+    output_samples: u64,
+}
+
+impl<I> Resampler<I>
+where
+    I: Iterator<Item = Result<Frame, SimplemadError>>,
+{
+    /// Resample the frames of `inner` to `target_rate`
+    pub fn new(inner: I, target_rate: u32, quality: Quality) -> Resampler<I> {
+        Resampler {
+            inner,
+            target_rate,
+            quality,
+            table: filter_table(quality),
+            segment: None,
+            last_frame: None,
+            output_samples: 0,
+        }
+    }
+
+    fn new_segment(&self, input_rate: u32, hold: &[f64], leftover: f64) -> Segment {
+        let step = f64::from(input_rate) / f64::from(self.target_rate);
+        let scale = if input_rate == self.target_rate {
+            1.0
+        } else {
+            step.recip().min(1.0) * self.quality.rolloff()
+        };
+        let half_width = self.quality.zero_crossings() as f64 / scale;
+
+        // The history starts with enough samples to center the filter on
+        // the first input sample
+        let pad = half_width.ceil() as usize;
+        Segment {
+            input_rate,
+            step,
+            scale,
+            half_width,
+            history: hold.iter().map(|&value| vec![value; pad]).collect(),
+            time: pad as f64 + leftover,
+        }
+    }
+
+    /// Interpolate a channel at `time`
+    fn interpolate(&self, segment: &Segment, channel: &[f64], time: f64) -> f64 {
+        let phases = self.quality.phases() as f64;
+        let first = (time - segment.half_width).ceil().max(0.0) as usize;
+        let last = ((time + segment.half_width).floor() as usize).min(channel.len() - 1);
+
+        let mut sum = 0.0;
+        for (idx, &sample) in channel.iter().enumerate().take(last + 1).skip(first) {
+            let position = (time - idx as f64).abs() * segment.scale * phases;
+            let entry = position as usize;
+            if entry + 1 >= self.table.len() {
+                continue;
+            }
+            let frac = position - entry as f64;
+            let h = self.table[entry] + frac * (self.table[entry + 1] - self.table[entry]);
+            sum += sample * h;
+        }
+        sum * segment.scale
+    }
+
+    /// Produce every output sample whose filter is covered by the history,
+    /// or when flushing every one before `end`
+    fn run(&self, segment: &mut Segment, output: &mut [Vec<MadFixed32>], end: Option<f64>) {
+        let len = segment.history[0].len() as f64;
+        loop {
+            let ready = match end {
+                Some(end) => segment.time < end,
+                None => segment.time + segment.half_width <= len - 1.0,
+            };
+            if !ready {
+                break;
+            }
+
+            for (channel, out) in segment.history.iter().zip(output.iter_mut()) {
+                let value = if segment.scale == 1.0 && segment.time.fract() == 0.0 {
+                    channel[segment.time as usize]
+                } else {
+                    self.interpolate(segment, channel, segment.time)
+                };
+                let value = value
+                    .round()
+                    .clamp(f64::from(i32::MIN), f64::from(i32::MAX));
+                out.push(MadFixed32::new(value as i32));
+            }
+            segment.time += segment.step;
+        }
+
+        // Drop the samples that no later output sample needs
+        let keep_from = (segment.time - segment.half_width).floor().max(0.0) as usize;
+        let keep_from = keep_from.min(segment.history[0].len());
+        for channel in &mut segment.history {
+            channel.drain(..keep_from);
+        }
+        segment.time -= keep_from as f64;
+    }
+
+    /// Play out the current segment to its last input sample, returning the
+    /// last sample of each channel and how far past the end the next output
+    /// sample falls, in seconds
+    fn finish_segment(&mut self, output: &mut [Vec<MadFixed32>]) -> Option<(Vec<f64>, f64)> {
+        let mut segment = self.segment.take()?;
+        let end = segment.history[0].len() as f64;
+        let hold: Vec<f64> = segment
+            .history
+            .iter()
+            .map(|channel| *channel.last().unwrap())
+            .collect();
+        let pad = segment.half_width.ceil() as usize + 1;
+        for (channel, &value) in segment.history.iter_mut().zip(&hold) {
+            channel.extend(std::iter::repeat_n(value, pad));
+        }
+
+        let before = segment.history[0].len();
+        self.run(&mut segment, output, Some(end));
+        let end = end - (before - segment.history[0].len()) as f64;
+        let leftover = (segment.time - end) / f64::from(segment.input_rate);
+        Some((hold, leftover))
+    }
+
+    fn resample(&mut self, mut frame: Frame) -> Frame {
+        let channels = frame.samples.len();
+        let mut output = vec![Vec::new(); channels];
+
+        let restart = match self.segment {
+            Some(ref segment) => {
+                segment.input_rate != frame.sample_rate || segment.history.len() != channels
+            }
+            None => true,
+        };
+        if restart {
+            let old_channels = self.segment.as_ref().map_or(0, |s| s.history.len());
+            let mut tail = vec![Vec::new(); old_channels];
+            let (hold, leftover) = self
+                .finish_segment(&mut tail)
+                .unwrap_or_else(|| (Vec::new(), 0.0));
+            // The rest of the old rate goes to the corresponding channels of
+            // this frame, or the last one if it had fewer
+            if old_channels > 0 {
+                output = (0..channels)
+                    .map(|ch| tail[ch.min(old_channels - 1)].clone())
+                    .collect();
+            }
+
+            let hold: Vec<f64> = (0..channels)
+                .map(|ch| match hold.len() {
+                    0 => 0.0,
+                    len => hold[ch.min(len - 1)],
+                })
+                .collect();
+            let leftover = leftover * f64::from(frame.sample_rate);
+            self.segment = Some(self.new_segment(frame.sample_rate, &hold, leftover));
+        }
+
+        let mut segment = self.segment.take().unwrap();
+        for (history, samples) in segment.history.iter_mut().zip(&frame.samples) {
+            history.extend(samples.iter().map(|s| f64::from(s.to_raw())));
+        }
+        self.run(&mut segment, &mut output, None);
+        self.segment = Some(segment);
+
+        frame.samples = Vec::new();
+        self.last_frame = Some(frame.clone());
+        self.output_frame(frame, output)
+    }
+
+    fn output_frame(&mut self, mut frame: Frame, samples: Vec<Vec<MadFixed32>>) -> Frame {
+        let len = samples.first().map_or(0, |ch| ch.len()) as u64;
+        frame.sample_rate = self.target_rate;
+        frame.position = samples_to_duration(self.output_samples, self.target_rate);
+        frame.duration = samples_to_duration(len, self.target_rate);
+        frame.samples = samples;
+        self.output_samples += len;
+        frame
+    }
+}
+
+impl<I> Iterator for Resampler<I>
+where
+    I: Iterator<Item = Result<Frame, SimplemadError>>,
+{
+    type Item = Result<Frame, SimplemadError>;
+
+    fn next(&mut self) -> Option<Result<Frame, SimplemadError>> {
+        match self.inner.next() {
+            Some(Ok(frame)) if frame.samples.is_empty() => Some(Ok(frame)),
+            Some(Ok(frame)) => Some(Ok(self.resample(frame))),
+            Some(Err(e)) => Some(Err(e)),
+            None => {
+                let frame = self.last_frame.take()?;
+                let channels = self.segment.as_ref().map_or(0, |s| s.history.len());
+                let mut output = vec![Vec::new(); channels];
+                self.finish_segment(&mut output);
+                Some(Ok(self.output_frame(frame, output)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Decoder, FIXED_ONE};
+    use std::fs::File;
+    use std::path::Path;
+
+    /// Frames of a stereo sine at 0.5 of full scale, continuing in phase
+    /// from `start` seconds
+    fn sine_frames(sample_rate: u32, frequency: f64, start: f64, count: usize) -> Vec<Frame> {
+        crate::test::sine_frames(sample_rate, 1152, 2, count, |t| {
+            0.5 * (2.0 * PI * frequency * (start + t)).sin()
+        })
+    }
+
+    fn resample(frames: Vec<Frame>, quality: Quality) -> Vec<f64> {
+        Resampler::new(frames.into_iter().map(Ok), 48000, quality)
+            .map(|r| r.unwrap())
+            .flat_map(|f| f.samples[0].clone())
+            .map(|s| f64::from(s.to_raw()) / FIXED_ONE)
+            .collect()
+    }
+
+    fn max_error(output: &[f64], frequency: f64, range: std::ops::Range<usize>) -> f64 {
+        range
+            .map(|i| {
+                let expected = 0.5 * (2.0 * PI * frequency * i as f64 / 48000.0).sin();
+                (output[i] - expected).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn upsamples_sine() {
+        let output = resample(sine_frames(44100, 1000.0, 0.0, 40), Quality::Medium);
+
+        assert_eq!(output.len(), (40 * 1152 * 48000usize).div_ceil(44100));
+        // Away from the edges, where the filter sees the ends of the input
+        assert!(max_error(&output, 1000.0, 100..output.len() - 100) < 1e-3);
+    }
+
+    #[test]
+    fn downsamples_with_quality() {
+        let fast = resample(sine_frames(96000, 1000.0, 0.0, 20), Quality::Fast);
+        let high = resample(sine_frames(96000, 1000.0, 0.0, 20), Quality::High);
+
+        assert_eq!(fast.len(), 20 * 1152 / 2);
+        let range = 200..fast.len() - 200;
+        assert!(max_error(&high, 1000.0, range.clone()) < max_error(&fast, 1000.0, range));
+        assert!(max_error(&high, 1000.0, 200..high.len() - 200) < 1e-4);
+    }
+
+    #[test]
+    fn passes_matching_rate_through() {
+        let frames = sine_frames(48000, 1000.0, 0.0, 3);
+        let expected: Vec<i32> = frames
+            .iter()
+            .flat_map(|f| &f.samples[0])
+            .map(|s| s.to_raw())
+            .collect();
+        let output: Vec<i32> = Resampler::new(frames.into_iter().map(Ok), 48000, Quality::Fast)
+            .flat_map(|f| f.unwrap().samples[0].clone())
+            .map(|s| s.to_raw())
+            .collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn joins_rate_changes() {
+        let first = sine_frames(44100, 500.0, 0.0, 20);
+        let first_secs = 20.0 * 1152.0 / 44100.0;
+        let second = sine_frames(32000, 500.0, first_secs, 20);
+        let second_secs = 20.0 * 1152.0 / 32000.0;
+
+        let mut frames = first;
+        frames.extend(second);
+        let output = resample(frames, Quality::Medium);
+
+        let expected_len = (first_secs + second_secs) * 48000.0;
+        assert!((output.len() as f64 - expected_len).abs() <= 2.0);
+        // The sine carries on across the join without a gap or jump
+        let join = (first_secs * 48000.0) as usize;
+        assert!(max_error(&output, 500.0, join - 500..join + 500) < 0.02);
+    }
+
+    #[test]
+    fn decoded_stream() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let decoder = Decoder::decode(File::open(path).unwrap()).unwrap();
+        let mut input = 0;
+        let mut output = 0;
+        let frames = decoder.inspect(|r| {
+            if let Ok(frame) = r {
+                input += frame.samples[0].len();
+            }
+        });
+        for frame in Resampler::new(frames, 48000, Quality::Fast) {
+            let frame = frame.unwrap();
+            assert_eq!(frame.sample_rate, 48000);
+            output += frame.samples[0].len();
+        }
+        assert_eq!(output, (input * 48000).div_ceil(44100));
+    }
+}
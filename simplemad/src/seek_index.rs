@@ -0,0 +1,288 @@
+//! Frame indexes for fast, exact seeking
+
+use crate::{
+    duration_to_samples, frame_sample_count, priming_frames, samples_to_duration, Decoder,
+    SimplemadError,
+};
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"SMIX";
+const VERSION: u8 = 1;
+
+/// The position of a frame in a stream
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SeekPoint {
+    /// Byte offset of the frame in the input
+    pub offset: u64,
+    /// Number of samples per channel before the frame
+    pub sample: u64,
+}
+
+/// A table of frame positions, built once with `SeekIndex::build` and
+/// passed to `Decoder::decode_with_index` for fast, exact seeking
+///
+/// Store the index with `write` and load it with `read`, or through
+/// serde with the `serde` feature.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SeekIndex {
+    sample_rate: u32,
+    interval: u32,
+    lead_samples: u64,
+    total_samples: u64,
+    points: Vec<SeekPoint>,
+}
+
+impl SeekIndex {
+    /// Scan the frame headers of a stream, recording the position of every
+    /// `interval`th frame
+    pub fn build<R: io::Read>(reader: R, interval: u32) -> Result<SeekIndex, SimplemadError> {
+        let interval = interval.max(1);
+        let mut decoder = Decoder::decode_headers(reader)?;
+        let mut index = SeekIndex {
+            sample_rate: 0,
+            interval,
+            lead_samples: 0,
+            total_samples: 0,
+            points: Vec::new(),
+        };
+        let mut frames_since_point = 0;
+
+        while let Some(result) = decoder.next() {
+            let frame = match result {
+                Ok(frame) => frame,
+                Err(SimplemadError::Mad(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            let header = decoder.header();
+
+            if frames_since_point == 0 {
+                index.points.push(SeekPoint {
+                    offset: frame.offset,
+                    sample: index.total_samples,
+                });
+            }
+            frames_since_point = (frames_since_point + 1) % interval;
+            if index.sample_rate == 0 {
+                index.sample_rate = header.sample_rate;
+            }
+
+            let frame_len = frame_sample_count(header);
+            let lead = frame_len * (priming_frames(header) + 1);
+            index.lead_samples = index.lead_samples.max(lead);
+            index.total_samples += frame_len;
+        }
+
+        Ok(index)
+    }
+
+    /// Number of samples per second
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Number of frames between seek points
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Total duration of the indexed stream
+    pub fn duration(&self) -> Duration {
+        samples_to_duration(self.total_samples, self.sample_rate)
+    }
+
+    /// The recorded seek points, in stream order
+    pub fn points(&self) -> &[SeekPoint] {
+        &self.points
+    }
+
+    /// The last seek point far enough ahead of `position` to prime the
+    /// decoder before it
+    pub(crate) fn point_before(&self, position: Duration) -> SeekPoint {
+        let target = duration_to_samples(position, self.sample_rate);
+        let count = self
+            .points
+            .iter()
+            .take_while(|p| p.sample + self.lead_samples <= target)
+            .count();
+        self.points
+            .get(count.saturating_sub(1))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Write the index in a compact binary format
+    pub fn write<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(32 + self.points.len() * 4);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        write_varint(&mut bytes, u64::from(self.sample_rate));
+        write_varint(&mut bytes, u64::from(self.interval));
+        write_varint(&mut bytes, self.lead_samples);
+        write_varint(&mut bytes, self.total_samples);
+        write_varint(&mut bytes, self.points.len() as u64);
+
+        // Points are stored as differences from the previous point
+        let mut previous = SeekPoint::default();
+        for point in &self.points {
+            write_varint(&mut bytes, point.offset - previous.offset);
+            write_varint(&mut bytes, point.sample - previous.sample);
+            previous = *point;
+        }
+
+        writer.write_all(&bytes)
+    }
+
+    /// Read an index written by `write`
+    pub fn read<R: io::Read>(mut reader: R) -> io::Result<SeekIndex> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(invalid_data("not a seek index"));
+        }
+
+        let sample_rate = read_u32_varint(&mut reader)?;
+        let interval = read_u32_varint(&mut reader)?;
+        let lead_samples = read_varint(&mut reader)?;
+        let total_samples = read_varint(&mut reader)?;
+        let count = read_varint(&mut reader)?;
+
+        let mut points = Vec::new();
+        let mut previous = SeekPoint::default();
+        for _ in 0..count {
+            let offset = previous.offset.checked_add(read_varint(&mut reader)?);
+            let sample = previous.sample.checked_add(read_varint(&mut reader)?);
+            let point = match (offset, sample) {
+                (Some(offset), Some(sample)) => SeekPoint { offset, sample },
+                _ => return Err(invalid_data("seek point out of range")),
+            };
+            points.push(point);
+            previous = point;
+        }
+
+        Ok(SeekIndex {
+            sample_rate,
+            interval,
+            lead_samples,
+            total_samples,
+            points,
+        })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Append `value` as an unsigned LEB128 varint
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint<R: io::Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+fn read_u32_varint<R: io::Read>(reader: &mut R) -> io::Result<u32> {
+    u32::try_from(read_varint(reader)?).map_err(|_| invalid_data("value out of range"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Frame;
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn build_and_round_trip() {
+        let path = Path::new("sample_mp3s/variable_stereo.mp3");
+        let index = SeekIndex::build(File::open(path).unwrap(), 10).unwrap();
+
+        assert_eq!(index.sample_rate(), 44100);
+        assert_eq!(index.points().len(), 20);
+        assert_eq!(index.points()[0], SeekPoint::default());
+        assert_eq!(index.points()[1].sample, 11520);
+
+        let mut bytes = Vec::new();
+        index.write(&mut bytes).unwrap();
+        assert!(bytes.len() < 100);
+        assert_eq!(SeekIndex::read(&bytes[..]).unwrap(), index);
+        assert!(SeekIndex::read(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn corrupt_index() {
+        let header = |sample_rate: u64, count: u64| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.push(VERSION);
+            for value in &[sample_rate, 1, 0, 0, count] {
+                write_varint(&mut bytes, *value);
+            }
+            bytes
+        };
+
+        // Offsets that overflow when the differences are summed
+        let mut bytes = header(44100, 2);
+        for _ in 0..2 {
+            write_varint(&mut bytes, u64::MAX);
+            write_varint(&mut bytes, 1);
+        }
+        let error = SeekIndex::read(&bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let bytes = header(u64::from(u32::MAX) + 1, 0);
+        let error = SeekIndex::read(&bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn seek_with_index_matches_linear_decode() {
+        let path = Path::new("sample_mp3s/variable_joint_stereo.mp3");
+        let index = SeekIndex::build(File::open(path).unwrap(), 4).unwrap();
+        let full: Vec<i32> = Decoder::decode(File::open(path).unwrap())
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .flat_map(|f: Frame| f.samples[0].clone())
+            .map(|s| s.to_raw())
+            .collect();
+
+        let mut decoder = Decoder::decode_with_index(File::open(path).unwrap(), index).unwrap();
+        for &start_ms in &[3_000u64, 500, 0, 4_320] {
+            decoder.seek(Duration::from_millis(start_ms)).unwrap();
+            let frame = decoder.get_frame().unwrap();
+            let start = (start_ms * 44100 / 1000) as usize;
+            let samples: Vec<i32> = frame.samples[0].iter().map(|s| s.to_raw()).collect();
+
+            assert_eq!(frame.position, Duration::from_millis(start_ms));
+            assert_eq!(&full[start..start + samples.len()], &samples[..]);
+        }
+    }
+
+    #[test]
+    fn seek_without_index() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let mut decoder = Decoder::decode(File::open(path).unwrap()).unwrap();
+        decoder.get_frame().unwrap();
+        decoder.seek(Duration::from_secs(2)).unwrap();
+
+        let frame = decoder.get_frame().unwrap();
+        assert_eq!(frame.position, Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,198 @@
+//! A `rodio::Source` backed by a `Decoder`
+
+use crate::probe::tagged_duration;
+use crate::{Decoder, SeekIndex, SimplemadError};
+use rodio::source::SeekError;
+use std::io;
+use std::time::Duration;
+
+type SeekFn<R> = fn(&mut Decoder<R>, Duration) -> Result<(), SimplemadError>;
+
+/// Plays the output of a `Decoder` through rodio as interleaved `i16`
+/// samples
+///
+/// Frames are decoded one at a time, so the channel count and sample rate
+/// may change at any frame boundary. Decoding errors are skipped.
+pub struct DecoderSource<R>
+where
+    R: io::Read,
+{
+    decoder: Decoder<R>,
+    seek: Option<SeekFn<R>>,
+    total_duration: Option<Duration>,
+    samples: Vec<i16>,
+    next_sample: usize,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl<R> DecoderSource<R>
+where
+    R: io::Read,
+{
+    /// Play a decoder that cannot seek, with an optional known duration
+    pub fn from_decoder(decoder: Decoder<R>, total_duration: Option<Duration>) -> DecoderSource<R> {
+        let mut source = DecoderSource {
+            decoder,
+            seek: None,
+            total_duration,
+            samples: Vec::new(),
+            next_sample: 0,
+            channels: 1,
+            sample_rate: 44100,
+        };
+        source.load_frame();
+        source
+    }
+
+    /// Decode the next frame with samples into the buffer, leaving it empty
+    /// at the end of the stream
+    fn load_frame(&mut self) {
+        self.samples.clear();
+        self.next_sample = 0;
+
+        while self.samples.is_empty() {
+            let frame = match self.decoder.next() {
+                Some(Ok(frame)) => frame,
+                Some(Err(SimplemadError::Mad(_))) => continue,
+                Some(Err(_)) | None => return,
+            };
+
+            let frame_len = frame.samples.first().map_or(0, |ch| ch.len());
+            self.channels = frame.samples.len() as u16;
+            self.sample_rate = frame.sample_rate;
+            self.samples.reserve(frame_len * frame.samples.len());
+            for idx in 0..frame_len {
+                for channel in &frame.samples {
+                    self.samples.push(channel[idx].to_i16());
+                }
+            }
+        }
+    }
+}
+
+impl<R> DecoderSource<R>
+where
+    R: io::Read + io::Seek,
+{
+    /// Play a seekable stream in full, taking its duration from a Xing or
+    /// VBRI tag if it has one
+    ///
+    /// Only the start of the stream is read before playback, so streams
+    /// without a tag have no known duration. Use `with_index` to get one
+    /// from a full scan.
+    pub fn new(mut reader: R) -> Result<DecoderSource<R>, SimplemadError> {
+        let start = reader.stream_position()?;
+        let total_duration = tagged_duration(&mut reader)?;
+        reader.seek(io::SeekFrom::Start(start))?;
+
+        let mut source = DecoderSource::from_decoder(Decoder::decode(reader)?, total_duration);
+        source.seek = Some(Decoder::seek);
+        Ok(source)
+    }
+
+    /// Play a seekable stream in full, seeking with a prebuilt index
+    pub fn with_index(reader: R, index: SeekIndex) -> Result<DecoderSource<R>, SimplemadError> {
+        let total_duration = Some(index.duration());
+        let decoder = Decoder::decode_with_index(reader, index)?;
+
+        let mut source = DecoderSource::from_decoder(decoder, total_duration);
+        source.seek = Some(Decoder::seek);
+        Ok(source)
+    }
+}
+
+impl<R> Iterator for DecoderSource<R>
+where
+    R: io::Read,
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.samples.get(self.next_sample)?;
+        self.next_sample += 1;
+
+        // Keep the buffer filled so that current_frame_len only reaches
+        // zero at the end of the stream
+        if self.next_sample == self.samples.len() {
+            self.load_frame();
+        }
+
+        Some(sample)
+    }
+}
+
+impl<R> rodio::Source for DecoderSource<R>
+where
+    R: io::Read,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples.len() - self.next_sample)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let seek = self.seek.ok_or(SeekError::NotSupported {
+            underlying_source: std::any::type_name::<Self>(),
+        })?;
+
+        seek(&mut self.decoder, pos).map_err(|e| SeekError::Other(Box::new(e)))?;
+        self.load_frame();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::probe;
+    use rodio::Source;
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn plays_whole_stream() {
+        let path = Path::new("sample_mp3s/constant_stereo_16.mp3");
+        let source = DecoderSource::new(File::open(path).unwrap()).unwrap();
+
+        assert_eq!(source.channels(), 2);
+        assert_eq!(source.sample_rate(), 24000);
+        assert_eq!(source.current_frame_len(), Some(1152));
+        // Without a tag the duration is unknown until the whole stream is read
+        assert_eq!(source.total_duration(), None);
+        assert_eq!(source.count(), 210 * 576 * 2);
+    }
+
+    #[test]
+    fn seeks() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let mut source = DecoderSource::new(File::open(path).unwrap()).unwrap();
+        let info = probe(File::open(path).unwrap()).unwrap();
+        assert_eq!(source.total_duration(), Some(info.duration));
+        source.try_seek(Duration::from_secs(4)).unwrap();
+
+        let remaining = source.count() / 2;
+        assert_eq!(remaining, 193 * 1152 - 4 * 44100);
+    }
+
+    #[test]
+    fn plain_reader_cannot_seek() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let decoder = Decoder::decode(io::BufReader::new(File::open(path).unwrap())).unwrap();
+        let mut source = DecoderSource::from_decoder(decoder, None);
+
+        assert!(source.try_seek(Duration::from_secs(1)).is_err());
+        assert_eq!(source.total_duration(), None);
+    }
+}
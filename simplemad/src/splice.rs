@@ -0,0 +1,513 @@
+//! Lossless cutting and joining of streams by copying whole frames
+
+use crate::crc::compute_crc;
+use crate::probe::{id3v2_size, read_fully};
+use crate::xing::{parse_xing, side_info_len, LameTag};
+use crate::{duration_to_samples, frame_sample_count, Decoder, SimplemadError, BUFFER_GUARD};
+use simplemad_sys::*;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+/// Layer III bit rates in kbps for MPEG-1 and for MPEG-2 and 2.5, by index
+const BIT_RATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// Length of a Xing tag with every field present
+const XING_LEN: usize = 120;
+
+/// Length of the LAME extension
+const LAME_LEN: usize = 36;
+
+/// A part of an input to copy
+pub struct Segment<R> {
+    /// The input stream
+    pub reader: R,
+    /// Frames that end after this time are copied
+    pub start: Duration,
+    /// Frames that begin before this time are copied, or every frame to
+    /// the end of the stream if `None`
+    pub end: Option<Duration>,
+}
+
+/// A frame chosen for the output
+struct PlannedFrame {
+    offset: u64,
+    len: usize,
+    /// Replacement for the start of the frame, up to the end of its side
+    /// information
+    patch: Option<Vec<u8>>,
+    samples: u64,
+}
+
+/// The frames chosen from one segment
+struct SegmentPlan {
+    frames: Vec<PlannedFrame>,
+    /// CRC-16 of the copied audio, continuing from the previous segment
+    music_crc: u16,
+    lame: Option<LameTag>,
+    from_start: bool,
+    to_end: bool,
+}
+
+/// Copy whole frames from each segment in turn to `writer`, without
+/// decoding or re-encoding any audio
+///
+/// Layer III frames at the start of each segment that depend on bit
+/// reservoir data from frames that were not copied have their side
+/// information cleared, so they decode as silence rather than as errors.
+/// For Layer III output a new Xing or Info tag frame is written first,
+/// with a LAME extension if the first segment has one. All segments must
+/// share an MPEG version, layer and sample rate. Returns the number of
+/// audio frames written.
+pub fn splice<R, W>(segments: &mut [Segment<R>], mut writer: W) -> Result<u64, SimplemadError>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let mut first_header: Option<MadHeader> = None;
+    let mut plans: Vec<SegmentPlan> = Vec::with_capacity(segments.len());
+    for segment in segments.iter_mut() {
+        let music_crc = plans.last().map_or(0, |p| p.music_crc);
+        plans.push(plan_segment(segment, &mut first_header, music_crc)?);
+    }
+    let header = first_header.ok_or(SimplemadError::EOF)?;
+
+    let frames = plans.iter().flat_map(|p| &p.frames);
+    let frame_count = frames.clone().count() as u64;
+    if header.layer() == Ok(MadLayer::LayerIII) {
+        let tag = tag_frame(&header, &plans)?;
+        writer.write_all(&tag)?;
+    }
+
+    let mut bytes = Vec::new();
+    for (segment, plan) in segments.iter_mut().zip(&plans) {
+        for frame in &plan.frames {
+            read_frame(&mut segment.reader, frame, &mut bytes)?;
+            writer.write_all(&bytes)?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(frame_count)
+}
+
+/// Choose the frames of a segment and patch those that cannot be decoded
+/// without the preceding frames
+fn plan_segment<R: Read + Seek>(
+    segment: &mut Segment<R>,
+    first_header: &mut Option<MadHeader>,
+    music_crc: u16,
+) -> Result<SegmentPlan, SimplemadError> {
+    let reader = &mut segment.reader;
+    let start = reader.stream_position()?;
+    let mut head = [0u8; 10];
+    let head_len = read_fully(reader, &mut head)?;
+    let audio_start = start + id3v2_size(&head[..head_len]);
+    reader.seek(SeekFrom::Start(audio_start))?;
+
+    let guard = [0u8; BUFFER_GUARD];
+    let mut decoder = Decoder::decode_headers((&mut *reader).chain(&guard[..]))?;
+    let mut plan = SegmentPlan {
+        frames: Vec::new(),
+        music_crc,
+        lame: None,
+        from_start: true,
+        to_end: true,
+    };
+    let mut sample_position = 0;
+    let mut reservoir = 0;
+    let mut first = true;
+
+    while let Some(result) = decoder.next() {
+        match result {
+            Ok(_) => {}
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(SimplemadError::EOF) => break,
+            Err(e) => return Err(e),
+        }
+        let header = *decoder.header();
+        let bytes = decoder.frame_bytes();
+
+        if first {
+            first = false;
+            if let Some(xing) = parse_xing(bytes, &header) {
+                plan.lame = xing.lame;
+                continue;
+            }
+        }
+
+        let samples = frame_sample_count(&header);
+        let frame_start = sample_position;
+        sample_position += samples;
+        let sample_rate = header.sample_rate;
+        if frame_start + samples <= duration_to_samples(segment.start, sample_rate) {
+            plan.from_start = false;
+            continue;
+        }
+        if let Some(end) = segment.end {
+            if frame_start >= duration_to_samples(end, sample_rate) {
+                plan.to_end = false;
+                break;
+            }
+        }
+
+        match first_header {
+            Some(ref expected) if !compatible(expected, &header) => {
+                return Err(invalid_input("segments have different formats"))
+            }
+            Some(_) => {}
+            None => *first_header = Some(header),
+        }
+
+        let mut patch = None;
+        if header.layer() == Ok(MadLayer::LayerIII) {
+            // libmad keeps no more main data than the current frame could
+            // have pointed back to, so a cleared frame resets the reservoir
+            let (mut main_data_begin, data_start) = main_data_begin(bytes, &header);
+            if main_data_begin > reservoir {
+                patch = Some(silence_side_info(bytes, &header, data_start));
+                main_data_begin = 0;
+            }
+            reservoir = main_data_begin + bytes.len().saturating_sub(data_start);
+        }
+
+        plan.music_crc = match patch {
+            Some(ref patch) => crc16_arc(crc16_arc(plan.music_crc, patch), &bytes[patch.len()..]),
+            None => crc16_arc(plan.music_crc, bytes),
+        };
+        plan.frames.push(PlannedFrame {
+            offset: audio_start + decoder.frame_offset(),
+            len: bytes.len(),
+            patch,
+            samples,
+        });
+    }
+
+    Ok(plan)
+}
+
+fn compatible(a: &MadHeader, b: &MadHeader) -> bool {
+    let version_flags = MAD_FLAG_LSF_EXT | MAD_FLAG_MPEG_2_5_EXT;
+    a.layer == b.layer
+        && a.sample_rate == b.sample_rate
+        && a.flags & version_flags == b.flags & version_flags
+}
+
+fn invalid_input(message: &str) -> SimplemadError {
+    SimplemadError::Read(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+/// The `main_data_begin` field of a Layer III frame, and the offset at
+/// which its main data area begins
+fn main_data_begin(frame: &[u8], header: &MadHeader) -> (usize, usize) {
+    let side_info = if header.flags & MAD_FLAG_PROTECTION != 0 {
+        6
+    } else {
+        4
+    };
+    let data_start = side_info + side_info_len(header);
+    let field = u16::from(frame[side_info]) << 8 | u16::from(frame[side_info + 1]);
+    let main_data_begin = if header.flags & MAD_FLAG_LSF_EXT != 0 {
+        field >> 8
+    } else {
+        field >> 7
+    };
+    (main_data_begin as usize, data_start)
+}
+
+/// The start of a Layer III frame with its side information cleared, so
+/// that it uses no main data and decodes as silence
+fn silence_side_info(frame: &[u8], header: &MadHeader, data_start: usize) -> Vec<u8> {
+    let side_info = data_start - side_info_len(header);
+    let mut patch = frame[..data_start].to_vec();
+    for byte in &mut patch[side_info..] {
+        *byte = 0;
+    }
+
+    let mut patched = patch.clone();
+    patched.extend_from_slice(&frame[data_start..]);
+    if let Some(crc) = compute_crc(&patched, header) {
+        patch[4..6].copy_from_slice(&crc.to_be_bytes());
+    }
+    patch
+}
+
+fn read_frame<R: Read + Seek>(
+    reader: &mut R,
+    frame: &PlannedFrame,
+    bytes: &mut Vec<u8>,
+) -> Result<(), SimplemadError> {
+    bytes.resize(frame.len, 0);
+    reader.seek(SeekFrom::Start(frame.offset))?;
+    reader.read_exact(bytes)?;
+    if let Some(ref patch) = frame.patch {
+        bytes[..patch.len()].copy_from_slice(patch);
+    }
+    Ok(())
+}
+
+/// A Xing or Info tag frame describing the planned output, with a LAME
+/// extension if the first segment had one
+fn tag_frame(header: &MadHeader, plans: &[SegmentPlan]) -> Result<Vec<u8>, SimplemadError> {
+    let frames: Vec<&PlannedFrame> = plans.iter().flat_map(|p| &p.frames).collect();
+    let lame = plans.first().and_then(|p| p.lame);
+    let data_start = 4 + side_info_len(header);
+    let needed = data_start + XING_LEN + if lame.is_some() { LAME_LEN } else { 0 };
+    let len = frame_header(header, needed, &mut Vec::new())?;
+
+    let mut tag = Vec::with_capacity(len);
+    frame_header(header, needed, &mut tag)?;
+    tag.resize(len, 0);
+
+    let audio_len: u64 = frames.iter().map(|f| f.len as u64).sum();
+    let total_len = len as u64 + audio_len;
+    let vbr = frames
+        .windows(2)
+        .any(|pair| pair[0].len.abs_diff(pair[1].len) > 1);
+
+    let mut pos = data_start;
+    tag[pos..pos + 4].copy_from_slice(if vbr { b"Xing" } else { b"Info" });
+    tag[pos + 4..pos + 8].copy_from_slice(&0xfu32.to_be_bytes());
+    tag[pos + 8..pos + 12].copy_from_slice(&(frames.len() as u32).to_be_bytes());
+    tag[pos + 12..pos + 16]
+        .copy_from_slice(&(total_len.min(u64::from(u32::MAX)) as u32).to_be_bytes());
+    pos += 16;
+
+    // The table of contents maps each percent of the duration to a byte
+    // position, in 256ths of the stream
+    let total_samples: u64 = frames.iter().map(|f| f.samples).sum();
+    let mut frame_idx = 0;
+    let mut samples_before = 0;
+    let mut bytes_before = len as u64;
+    for percent in 0..100 {
+        let target = total_samples * percent / 100;
+        while frame_idx < frames.len() && samples_before + frames[frame_idx].samples <= target {
+            samples_before += frames[frame_idx].samples;
+            bytes_before += frames[frame_idx].len as u64;
+            frame_idx += 1;
+        }
+        tag[pos + percent as usize] = (bytes_before * 256 / total_len).min(255) as u8;
+    }
+    pos += 100;
+    // Quality indicator, unknown
+    pos += 4;
+
+    if let Some(lame) = lame {
+        let delay = if plans[0].from_start {
+            lame.encoder_delay
+        } else {
+            0
+        };
+        let padding = match plans.last() {
+            Some(last) if last.to_end => last.lame.map_or(0, |l| l.padding),
+            _ => 0,
+        };
+        let music_crc = plans.last().map_or(0, |p| p.music_crc);
+
+        // Keep the source's encoder settings and patch what has changed
+        tag[pos..pos + LAME_LEN].copy_from_slice(&lame.bytes);
+        tag[pos + 21] = (delay >> 4) as u8;
+        tag[pos + 22] = ((delay & 0x0f) << 4) as u8 | (padding >> 8) as u8;
+        tag[pos + 23] = padding as u8;
+        tag[pos + 28..pos + 32]
+            .copy_from_slice(&(total_len.min(u64::from(u32::MAX)) as u32).to_be_bytes());
+        tag[pos + 32..pos + 34].copy_from_slice(&music_crc.to_be_bytes());
+
+        let crc = crc16_arc(0, &tag[..pos + 34]);
+        tag[pos + 34..pos + 36].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    Ok(tag)
+}
+
+/// Append a Layer III frame header like `header` with the lowest bit rate
+/// whose frames hold `needed` bytes, returning the frame length
+fn frame_header(
+    header: &MadHeader,
+    needed: usize,
+    out: &mut Vec<u8>,
+) -> Result<usize, SimplemadError> {
+    let lsf = header.flags & MAD_FLAG_LSF_EXT != 0;
+    let (version, sample_rates): (u8, [u32; 3]) = if header.flags & MAD_FLAG_MPEG_2_5_EXT != 0 {
+        (0b00, [11025, 12000, 8000])
+    } else if lsf {
+        (0b10, [22050, 24000, 16000])
+    } else {
+        (0b11, [44100, 48000, 32000])
+    };
+    let rate_idx = sample_rates
+        .iter()
+        .position(|&r| r == header.sample_rate)
+        .ok_or_else(|| invalid_input("unsupported sample rate"))?;
+    let slots = if lsf { 72 } else { 144 };
+
+    for (bit_rate_idx, &kbps) in BIT_RATES[lsf as usize].iter().enumerate().skip(1) {
+        let len = (slots * kbps * 1000 / header.sample_rate) as usize;
+        if len >= needed {
+            let mode = match header.mode() {
+                Ok(MadMode::SingleChannel) => 3,
+                Ok(MadMode::DualChannel) => 2,
+                Ok(MadMode::JointStereo) => 1,
+                Ok(MadMode::Stereo) => 0,
+                Err(value) => return Err(SimplemadError::Unknown(value)),
+            };
+            out.extend_from_slice(&[
+                0xff,
+                0xe0 | version << 3 | 0b01 << 1 | 1,
+                (bit_rate_idx as u8) << 4 | (rate_idx as u8) << 2,
+                mode << 6,
+            ]);
+            return Ok(len);
+        }
+    }
+
+    Err(invalid_input("no bit rate can hold the tag"))
+}
+
+/// CRC-16 with the reflected 0x8005 polynomial, as used by the LAME tag
+fn crc16_arc(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{probe, validate_full, InfoSource};
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn open(name: &str) -> File {
+        File::open(Path::new("sample_mp3s").join(name)).unwrap()
+    }
+
+    /// The raw samples of every frame
+    fn decode(bytes: &[u8]) -> Vec<Vec<i32>> {
+        Decoder::decode(bytes)
+            .unwrap()
+            .map(|r| {
+                r.unwrap()
+                    .samples
+                    .concat()
+                    .iter()
+                    .map(|s| s.to_raw())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn whole_stream_is_unchanged() {
+        let mut segments = [Segment {
+            reader: open("constant_stereo_128.mp3"),
+            start: Duration::new(0, 0),
+            end: None,
+        }];
+        let mut output = Vec::new();
+        assert_eq!(splice(&mut segments, &mut output).unwrap(), 193);
+
+        let original = std::fs::read("sample_mp3s/constant_stereo_128.mp3").unwrap();
+        let spliced = decode(&output);
+        let expected = decode(&original);
+        assert_eq!(spliced.len(), expected.len());
+        assert!(spliced.iter().zip(&expected).skip(1).all(|(a, b)| a == b));
+
+        // The regenerated tag keeps the gapless information
+        let xing = {
+            let mut decoder = Decoder::decode_headers(&output[..]).unwrap();
+            decoder.get_frame().unwrap();
+            parse_xing(decoder.frame_bytes(), decoder.header()).unwrap()
+        };
+        let lame = xing.lame.unwrap();
+        assert_eq!((lame.encoder_delay, lame.padding), (576, 704));
+        assert_eq!(xing.bytes, Some(output.len() as u32));
+
+        // Apart from the lengths and CRCs, the LAME extension is copied
+        let source = {
+            let mut decoder = Decoder::decode_headers(&original[..]).unwrap();
+            decoder.get_frame().unwrap();
+            parse_xing(decoder.frame_bytes(), decoder.header())
+                .unwrap()
+                .lame
+                .unwrap()
+        };
+        assert_eq!(lame.bytes[..28], source.bytes[..28]);
+        let mut decoder = Decoder::decode_headers(&output[..]).unwrap();
+        decoder.get_frame().unwrap();
+        let frame = decoder.frame_bytes();
+        let crc_pos = frame.windows(4).position(|w| w == b"LAME").unwrap() + 34;
+        assert_eq!(
+            crc16_arc(0, &frame[..crc_pos]).to_be_bytes(),
+            frame[crc_pos..crc_pos + 2]
+        );
+    }
+
+    #[test]
+    fn cuts_and_joins_cleanly() {
+        let mut segments = [
+            Segment {
+                reader: open("variable_stereo.mp3"),
+                start: Duration::from_millis(1_500),
+                end: Some(Duration::from_millis(3_000)),
+            },
+            Segment {
+                reader: open("constant_stereo_128.mp3"),
+                start: Duration::from_secs(1),
+                end: Some(Duration::from_secs(2)),
+            },
+        ];
+        let mut output = Vec::new();
+        let frames = splice(&mut segments, &mut output).unwrap();
+        assert_eq!(frames, 58 + 39);
+
+        // Every frame decodes, including those cut off from their bit
+        // reservoir data
+        let report = validate_full(Cursor::new(&output)).unwrap();
+        assert!(report.is_valid(), "{:?}", report.issues);
+
+        let info = probe(&output[..]).unwrap();
+        assert_eq!(info.source, InfoSource::Xing);
+        assert_eq!(info.frame_count, frames);
+
+        let lame = {
+            let mut decoder = Decoder::decode_headers(&output[..]).unwrap();
+            decoder.get_frame().unwrap();
+            parse_xing(decoder.frame_bytes(), decoder.header())
+                .unwrap()
+                .lame
+                .unwrap()
+        };
+        assert_eq!((lame.encoder_delay, lame.padding), (0, 0));
+    }
+
+    #[test]
+    fn rejects_mixed_formats() {
+        let mut segments = [
+            Segment {
+                reader: open("constant_stereo_128.mp3"),
+                start: Duration::new(0, 0),
+                end: None,
+            },
+            Segment {
+                reader: open("constant_stereo_16.mp3"),
+                start: Duration::new(0, 0),
+                end: None,
+            },
+        ];
+        assert!(splice(&mut segments, io::sink()).is_err());
+    }
+}
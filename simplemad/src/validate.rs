@@ -0,0 +1,432 @@
+//! Checks for damaged or badly formed streams
+
+use crate::probe::{id3v2_size, read_fully, trailing_tag_sizes, TAIL_LEN};
+use crate::xing::parse_xing;
+use crate::{Decoder, SimplemadError, BUFFER_GUARD};
+use simplemad_sys::*;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A problem found in a stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct Issue {
+    /// Byte offset of the problem in the input
+    pub offset: u64,
+    /// What the problem is
+    pub kind: IssueKind,
+}
+
+/// The kinds of problem found by `validate`
+#[derive(Clone, Debug, PartialEq)]
+pub enum IssueKind {
+    /// Data before the first frame that is not an ID3v2 tag
+    LeadingGarbage {
+        /// Length of the data in bytes
+        len: u64,
+    },
+    /// Data after the last frame that is not an APEv2 or ID3v1 tag
+    TrailingGarbage {
+        /// Length of the data in bytes
+        len: u64,
+    },
+    /// A frame cut short by the end of the stream
+    TruncatedFrame {
+        /// Number of bytes of the frame present
+        len: u64,
+    },
+    /// Data between two frames, skipped to find the next frame
+    Resync {
+        /// Length of the data in bytes
+        skipped: u64,
+    },
+    /// A frame failed its CRC check
+    CrcFailure,
+    /// A frame could not be decoded
+    DecodeError(MadError),
+    /// The frame count in a Xing or Info tag does not match the stream
+    FrameCountMismatch {
+        /// Number of frames recorded in the tag
+        tagged: u64,
+        /// Number of frames found
+        actual: u64,
+    },
+    /// The sample rate differs from that of the previous frame
+    SampleRateChange {
+        /// Sample rate of the previous frame
+        from: u32,
+        /// Sample rate of this frame
+        to: u32,
+    },
+    /// The channel mode differs from that of the previous frame
+    ModeChange {
+        /// Mode of the previous frame
+        from: MadMode,
+        /// Mode of this frame
+        to: MadMode,
+    },
+    /// Tag data that is not an ID3v2, APEv2 or ID3v1 tag, such as Lyrics3
+    /// or an unsupported ID3v2 version
+    UnknownTag {
+        /// Length of the data in bytes
+        len: u64,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: ", self.offset)?;
+        match self.kind {
+            IssueKind::LeadingGarbage { len } => write!(f, "{} bytes of leading garbage", len),
+            IssueKind::TrailingGarbage { len } => write!(f, "{} bytes of trailing garbage", len),
+            IssueKind::TruncatedFrame { len } => write!(f, "truncated frame of {} bytes", len),
+            IssueKind::Resync { skipped } => write!(f, "lost sync for {} bytes", skipped),
+            IssueKind::CrcFailure => write!(f, "CRC check failed"),
+            IssueKind::DecodeError(error) => write!(f, "decoding failed: {:?}", error),
+            IssueKind::FrameCountMismatch { tagged, actual } => write!(
+                f,
+                "tag records {} frames but the stream has {}",
+                tagged, actual
+            ),
+            IssueKind::SampleRateChange { from, to } => {
+                write!(f, "sample rate changes from {} to {}", from, to)
+            }
+            IssueKind::ModeChange { from, to } => {
+                write!(f, "mode changes from {:?} to {:?}", from, to)
+            }
+            IssueKind::UnknownTag { len } => write!(f, "unknown tag of {} bytes", len),
+        }
+    }
+}
+
+/// The result of validating a stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationReport {
+    /// Number of audio frames, not counting a Xing or Info tag frame
+    pub frame_count: u64,
+    /// Problems found, in stream order for each pass
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    /// Whether no problems were found
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check the structure of a stream by walking its frame headers, verifying
+/// the CRC of protected frames
+///
+/// Returns `SimplemadError::EOF` if the stream contains no MPEG audio.
+pub fn validate<R: Read + Seek>(reader: R) -> Result<ValidationReport, SimplemadError> {
+    check(reader, false)
+}
+
+/// Check the structure of a stream as `validate` does, then decode every
+/// frame to find damaged audio data
+pub fn validate_full<R: Read + Seek>(reader: R) -> Result<ValidationReport, SimplemadError> {
+    check(reader, true)
+}
+
+fn check<R: Read + Seek>(mut reader: R, full: bool) -> Result<ValidationReport, SimplemadError> {
+    let start = reader.stream_position()?;
+    let total = reader.seek(SeekFrom::End(0))? - start;
+    let mut issues = Vec::new();
+
+    let head = read_at(&mut reader, start, 10)?;
+    let audio_start = id3v2_size(&head).min(total);
+    if audio_start > 0 && !(2..=4).contains(&head[3]) {
+        issues.push(Issue {
+            offset: 0,
+            kind: IssueKind::UnknownTag { len: audio_start },
+        });
+    }
+
+    let tail_start = total.saturating_sub(TAIL_LEN as u64).max(audio_start);
+    let tail = read_at(&mut reader, start + tail_start, TAIL_LEN)?;
+    let (ape_size, id3v1_size) = trailing_tag_sizes(&tail);
+    let audio_end = total.saturating_sub(ape_size + id3v1_size).max(audio_start);
+
+    let walk = walk_headers(&mut reader, start + audio_start, audio_end - audio_start)?;
+    let first_offset = walk.first_offset.ok_or(SimplemadError::EOF)?;
+    if first_offset > 0 {
+        issues.push(Issue {
+            offset: audio_start,
+            kind: IssueKind::LeadingGarbage { len: first_offset },
+        });
+    }
+    for issue in walk.issues {
+        issues.push(Issue {
+            offset: audio_start + issue.offset,
+            kind: issue.kind,
+        });
+    }
+
+    if let Some(tagged) = walk.tagged_frames {
+        if tagged != walk.frame_count {
+            issues.push(Issue {
+                offset: audio_start + first_offset,
+                kind: IssueKind::FrameCountMismatch {
+                    tagged,
+                    actual: walk.frame_count,
+                },
+            });
+        }
+    }
+
+    let frames_end = audio_start + walk.end;
+    if frames_end < audio_end {
+        let len = audio_end - frames_end;
+        let rest = read_at(&mut reader, start + frames_end, 11)?;
+        let kind = if rest.len() >= 2 && rest[0] == 0xff && rest[1] & 0xe0 == 0xe0 {
+            IssueKind::TruncatedFrame { len }
+        } else if [&b"ID3"[..], b"TAG+", b"LYRICSBEGIN"]
+            .iter()
+            .any(|magic| rest.starts_with(magic))
+        {
+            IssueKind::UnknownTag { len }
+        } else {
+            IssueKind::TrailingGarbage { len }
+        };
+        issues.push(Issue {
+            offset: frames_end,
+            kind,
+        });
+    }
+
+    if full {
+        let guard = [0u8; BUFFER_GUARD];
+        reader.seek(SeekFrom::Start(start + audio_start))?;
+        let audio = (&mut reader).take(audio_end - audio_start);
+        let mut decoder = Decoder::decode(audio.chain(&guard[..]))?;
+
+        while let Some(result) = decoder.next() {
+            let error = match result {
+                Err(SimplemadError::Mad(error)) => error,
+                Err(e) => return Err(e),
+                Ok(_) => continue,
+            };
+
+            // Sync errors and CRC failures were found by the header walk
+            if (error as i32) < 0x0200 || error == MadError::BadCRC {
+                continue;
+            }
+            issues.push(Issue {
+                offset: audio_start + decoder.frame_offset(),
+                kind: IssueKind::DecodeError(error),
+            });
+        }
+    }
+
+    Ok(ValidationReport {
+        frame_count: walk.frame_count,
+        issues,
+    })
+}
+
+/// Read up to `len` bytes at `offset`
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    let read = read_fully(reader, &mut bytes)?;
+    bytes.truncate(read);
+    Ok(bytes)
+}
+
+/// The findings of a walk over the frame headers, with offsets relative
+/// to the start of the audio
+#[derive(Default)]
+struct HeaderWalk {
+    first_offset: Option<u64>,
+    end: u64,
+    frame_count: u64,
+    tagged_frames: Option<u64>,
+    issues: Vec<Issue>,
+}
+
+fn walk_headers<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: u64,
+) -> Result<HeaderWalk, SimplemadError> {
+    let guard = [0u8; BUFFER_GUARD];
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut decoder = Decoder::decode_headers(reader.take(len).chain(&guard[..]))?.with_crc_check();
+    let mut walk = HeaderWalk::default();
+    let mut previous: Option<(u32, MadMode)> = None;
+
+    while let Some(result) = decoder.next() {
+        let frame = match result {
+            Ok(frame) => frame,
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(e) => return Err(e),
+        };
+
+        if walk.first_offset.is_none() {
+            walk.first_offset = Some(frame.offset);
+        } else if frame.offset > walk.end {
+            walk.issues.push(Issue {
+                offset: walk.end,
+                kind: IssueKind::Resync {
+                    skipped: frame.offset - walk.end,
+                },
+            });
+        }
+        walk.end = frame.offset + decoder.frame_bytes().len() as u64;
+        if frame.crc_ok == Some(false) {
+            walk.issues.push(Issue {
+                offset: frame.offset,
+                kind: IssueKind::CrcFailure,
+            });
+        }
+
+        if walk.frame_count == 0 && walk.first_offset == Some(frame.offset) {
+            if let Some(xing) = parse_xing(decoder.frame_bytes(), decoder.header()) {
+                // A tag without the frame count flag makes no claim
+                walk.tagged_frames = xing.frames.map(u64::from);
+                continue;
+            }
+        }
+
+        if let Some((sample_rate, mode)) = previous {
+            if frame.sample_rate != sample_rate {
+                walk.issues.push(Issue {
+                    offset: frame.offset,
+                    kind: IssueKind::SampleRateChange {
+                        from: sample_rate,
+                        to: frame.sample_rate,
+                    },
+                });
+            }
+            if frame.mode != mode {
+                walk.issues.push(Issue {
+                    offset: frame.offset,
+                    kind: IssueKind::ModeChange {
+                        from: mode,
+                        to: frame.mode,
+                    },
+                });
+            }
+        }
+        previous = Some((frame.sample_rate, frame.mode));
+        walk.frame_count += 1;
+    }
+
+    Ok(walk)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn sample(name: &str) -> Vec<u8> {
+        fs::read(format!("sample_mp3s/{}", name)).unwrap()
+    }
+
+    fn kinds(report: &ValidationReport) -> Vec<IssueKind> {
+        report.issues.iter().map(|i| i.kind.clone()).collect()
+    }
+
+    #[test]
+    fn clean_streams() {
+        for name in &[
+            "constant_stereo_128.mp3",
+            "variable_joint_stereo.mp3",
+            "rodiomusic.mp3",
+        ] {
+            let report = validate_full(Cursor::new(sample(name))).unwrap();
+            assert!(report.is_valid(), "{}: {:?}", name, report.issues);
+        }
+
+        let report = validate(Cursor::new(sample("constant_stereo_128.mp3"))).unwrap();
+        assert_eq!(report.frame_count, 193);
+    }
+
+    #[test]
+    fn garbage_around_frames() {
+        let mut bytes = vec![0x55; 100];
+        bytes.extend(sample("constant_stereo_128.mp3"));
+        let audio_end = bytes.len() as u64;
+        bytes.extend_from_slice(&[0x55; 40]);
+
+        let report = validate(Cursor::new(bytes)).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue {
+                    offset: 0,
+                    kind: IssueKind::LeadingGarbage { len: 100 },
+                },
+                Issue {
+                    offset: audio_end,
+                    kind: IssueKind::TrailingGarbage { len: 40 },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resync_and_truncation() {
+        let mut bytes = sample("constant_stereo_128.mp3");
+        let len = bytes.len();
+        bytes.truncate(len - 100);
+        let tail = bytes.split_off(20_000);
+        bytes.extend_from_slice(&[0x55; 30]);
+        bytes.extend(tail);
+
+        let report = validate(Cursor::new(bytes)).unwrap();
+        let kinds = kinds(&report);
+        assert_eq!(report.frame_count, 192);
+        assert!(kinds.contains(&IssueKind::FrameCountMismatch {
+            tagged: 193,
+            actual: 192,
+        }));
+        assert!(kinds.iter().any(|k| matches!(k, IssueKind::Resync { .. })));
+        assert!(kinds
+            .iter()
+            .any(|k| matches!(k, IssueKind::TruncatedFrame { .. })));
+    }
+
+    #[test]
+    fn tag_without_frame_count() {
+        let mut bytes = sample("constant_stereo_128.mp3");
+        // Clear the frame count flag of the Info tag
+        assert_eq!(&bytes[36..40], b"Info");
+        bytes[43] &= !0x01;
+
+        let report = validate(Cursor::new(bytes)).unwrap();
+        assert_eq!(report.frame_count, 193);
+        assert!(report.is_valid(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn unknown_trailing_tag() {
+        let mut bytes = sample("constant_stereo_128.mp3");
+        bytes.extend_from_slice(b"LYRICSBEGIN some lyrics");
+
+        let report = validate(Cursor::new(bytes)).unwrap();
+        assert_eq!(kinds(&report), vec![IssueKind::UnknownTag { len: 23 }]);
+    }
+
+    #[test]
+    fn mixed_sample_rates() {
+        let mut bytes = sample("constant_stereo_128.mp3");
+        bytes.extend(sample("constant_stereo_16.mp3"));
+
+        let report = validate(Cursor::new(bytes)).unwrap();
+        assert_eq!(
+            report.issues[0].kind,
+            IssueKind::SampleRateChange {
+                from: 44100,
+                to: 24000,
+            }
+        );
+    }
+
+    #[test]
+    fn no_audio() {
+        assert!(validate(Cursor::new(vec![0u8; 1000])).is_err());
+    }
+}
@@ -0,0 +1,490 @@
+//! Decoding to WAV files and raw PCM
+
+use crate::xing::parse_xing;
+use crate::{frame_sample_count, Decoder, Frame, MadFixed32, SimplemadError, BUFFER_GUARD};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Delay in samples of libmad's synthesis filter, which LAME accounts for
+/// when recording the encoder delay
+const DECODER_DELAY: u64 = 529;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Trailing bytes of the KSDATAFORMAT_SUBTYPE GUIDs, after the format tag
+const SUBFORMAT_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+/// The sample format of a WAV file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// 16-bit signed integer
+    Int16,
+    /// 24-bit signed integer
+    Int24,
+    /// 32-bit signed integer
+    Int32,
+    /// 32-bit IEEE float
+    Float32,
+}
+
+impl SampleFormat {
+    fn bits(self) -> u16 {
+        match self {
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+            SampleFormat::Int32 | SampleFormat::Float32 => 32,
+        }
+    }
+
+    fn format_tag(self) -> u16 {
+        match self {
+            SampleFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+            _ => WAVE_FORMAT_PCM,
+        }
+    }
+}
+
+/// Decode an MPEG audio stream to a WAV file
+///
+/// The sample rate and channel count are taken from the first frame.
+/// The encoder delay and padding recorded in a LAME tag are trimmed, so
+/// the output matches the length of the original audio. Returns the
+/// number of samples per channel written.
+pub fn decode_to_wav<R, W>(
+    reader: R,
+    writer: W,
+    format: SampleFormat,
+) -> Result<u64, SimplemadError>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    write_wav(reader, writer, format, false)
+}
+
+/// Decode to a WAV file as `decode_to_wav` does, adding triangular dither
+/// when quantizing to 16 or 24 bits
+pub fn decode_to_wav_dithered<R, W>(
+    reader: R,
+    writer: W,
+    format: SampleFormat,
+) -> Result<u64, SimplemadError>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    write_wav(reader, writer, format, true)
+}
+
+/// Decode an MPEG audio stream to raw interleaved little-endian samples,
+/// trimmed as `decode_to_wav` does
+///
+/// Returns the number of samples per channel written.
+pub fn decode_to_raw<R, W>(
+    reader: R,
+    writer: W,
+    format: SampleFormat,
+) -> Result<u64, SimplemadError>
+where
+    R: Read,
+    W: Write,
+{
+    write_raw(reader, writer, format, false)
+}
+
+/// Decode to raw samples as `decode_to_raw` does, adding triangular dither
+/// when quantizing to 16 or 24 bits
+pub fn decode_to_raw_dithered<R, W>(
+    reader: R,
+    writer: W,
+    format: SampleFormat,
+) -> Result<u64, SimplemadError>
+where
+    R: Read,
+    W: Write,
+{
+    write_raw(reader, writer, format, true)
+}
+
+fn write_wav<R, W>(
+    reader: R,
+    mut writer: W,
+    format: SampleFormat,
+    dither: bool,
+) -> Result<u64, SimplemadError>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    let mut wav = None;
+    let (sample_count, data_len) =
+        write_samples(reader, &mut writer, format, dither, |writer, frame| {
+            wav = Some(WavWriter::start(writer, format, frame)?);
+            Ok(())
+        })?;
+
+    let mut wav = wav.ok_or(SimplemadError::EOF)?;
+    wav.data_len = data_len;
+    wav.finish(&mut writer)?;
+    Ok(sample_count)
+}
+
+fn write_raw<R, W>(
+    reader: R,
+    mut writer: W,
+    format: SampleFormat,
+    dither: bool,
+) -> Result<u64, SimplemadError>
+where
+    R: Read,
+    W: Write,
+{
+    let (sample_count, _) = write_samples(reader, &mut writer, format, dither, |_, _| Ok(()))?;
+    writer.flush()?;
+    Ok(sample_count)
+}
+
+/// Decode, trim and quantize the stream into `writer`, calling `start`
+/// with the first frame before any samples are written
+///
+/// Returns the number of samples per channel and of bytes written.
+fn write_samples<R, W, F>(
+    reader: R,
+    writer: &mut W,
+    format: SampleFormat,
+    dither: bool,
+    start: F,
+) -> Result<(u64, u64), SimplemadError>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(&mut W, &Frame) -> io::Result<()>,
+{
+    let guard = [0u8; BUFFER_GUARD];
+    let mut decoder = Decoder::decode(reader.chain(&guard[..]))?;
+    let mut quantizer = Quantizer::new(format, dither);
+    let mut start = Some(start);
+    let mut channels = 0;
+    let mut skip = 0;
+    let mut remaining = u64::MAX;
+    let mut sample_count = 0;
+    let mut data_len = 0;
+    let mut bytes = Vec::new();
+
+    while let Some(result) = decoder.next() {
+        let frame = match result {
+            Ok(frame) => frame,
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(start) = start.take() {
+            start(writer, &frame)?;
+            channels = frame.samples.len().max(1);
+
+            // A Xing or Info tag frame decodes to silence
+            if let Some(xing) = parse_xing(decoder.frame_bytes(), decoder.header()) {
+                if let (Some(lame), Some(frames)) = (xing.lame, xing.frames) {
+                    let delay = u64::from(lame.encoder_delay);
+                    let total = u64::from(frames) * frame_sample_count(decoder.header());
+                    skip = delay + DECODER_DELAY;
+                    remaining = total.saturating_sub(delay + u64::from(lame.padding));
+                }
+                continue;
+            }
+        }
+
+        let frame_len = frame.samples.first().map_or(0, |ch| ch.len()) as u64;
+        let first = skip.min(frame_len);
+        let last = frame_len.min(first + remaining);
+        skip -= first;
+        remaining -= last - first;
+
+        bytes.clear();
+        for idx in first as usize..last as usize {
+            for ch in 0..channels {
+                let channel = frame.samples.get(ch).unwrap_or(&frame.samples[0]);
+                quantizer.write(&mut bytes, channel[idx]);
+            }
+        }
+        writer.write_all(&bytes)?;
+        data_len += bytes.len() as u64;
+        sample_count += last - first;
+
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    if start.is_some() {
+        return Err(SimplemadError::EOF);
+    }
+    Ok((sample_count, data_len))
+}
+
+/// Tracks the chunk sizes that are patched once the data is written
+struct WavWriter {
+    start: u64,
+    data_len: u64,
+    data_len_pos: u64,
+}
+
+impl WavWriter {
+    fn start<W: Write + Seek>(
+        writer: &mut W,
+        format: SampleFormat,
+        frame: &Frame,
+    ) -> io::Result<WavWriter> {
+        let start = writer.stream_position()?;
+        let channels = frame.samples.len().max(1) as u16;
+        let bits = format.bits();
+        let block_align = channels * bits / 8;
+        let extensible = bits > 16;
+
+        let mut header = Vec::with_capacity(68);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&(if extensible { 40u32 } else { 16 }).to_le_bytes());
+        let format_tag = if extensible {
+            WAVE_FORMAT_EXTENSIBLE
+        } else {
+            format.format_tag()
+        };
+        header.extend_from_slice(&format_tag.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&frame.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(frame.sample_rate * u32::from(block_align)).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        if extensible {
+            header.extend_from_slice(&22u16.to_le_bytes());
+            header.extend_from_slice(&bits.to_le_bytes());
+            header.extend_from_slice(&channel_mask(channels).to_le_bytes());
+            header.extend_from_slice(&format.format_tag().to_le_bytes());
+            header.extend_from_slice(&SUBFORMAT_GUID_TAIL);
+        }
+
+        header.extend_from_slice(b"data");
+        let data_len_pos = start + header.len() as u64;
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(WavWriter {
+            start,
+            data_len: 0,
+            data_len_pos,
+        })
+    }
+
+    /// Pad the data chunk to an even length and patch the chunk sizes
+    fn finish<W: Write + Seek>(&self, writer: &mut W) -> io::Result<()> {
+        if self.data_len % 2 == 1 {
+            writer.write_all(&[0])?;
+        }
+        let end = writer.stream_position()?;
+
+        writer.seek(SeekFrom::Start(self.start + 4))?;
+        writer.write_all(&chunk_size(end - self.start - 8).to_le_bytes())?;
+        writer.seek(SeekFrom::Start(self.data_len_pos))?;
+        writer.write_all(&chunk_size(self.data_len).to_le_bytes())?;
+        writer.seek(SeekFrom::Start(end))?;
+        writer.flush()
+    }
+}
+
+/// Chunk sizes saturate for streams too long for the format
+fn chunk_size(len: u64) -> u32 {
+    len.min(u64::from(u32::MAX)) as u32
+}
+
+/// Speaker positions for the extensible format: front center for mono,
+/// front left and right otherwise
+fn channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => 0x4,
+        2 => 0x3,
+        _ => 0,
+    }
+}
+
+/// Converts samples to the output format, with optional TPDF dither
+struct Quantizer {
+    format: SampleFormat,
+    dither: bool,
+    seed: u32,
+}
+
+impl Quantizer {
+    fn new(format: SampleFormat, dither: bool) -> Quantizer {
+        Quantizer {
+            format,
+            dither,
+            seed: 0x2545_f491,
+        }
+    }
+
+    fn write(&mut self, bytes: &mut Vec<u8>, sample: MadFixed32) {
+        match self.format {
+            SampleFormat::Int16 => {
+                let value = self.quantize(sample, 16) as i16;
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            SampleFormat::Int24 => {
+                let value = self.quantize(sample, 24);
+                bytes.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+            SampleFormat::Int32 => bytes.extend_from_slice(&sample.to_i32().to_le_bytes()),
+            SampleFormat::Float32 => bytes.extend_from_slice(&sample.to_f32().to_le_bytes()),
+        }
+    }
+
+    /// Round to `bits` bits, clipping to the full scale range
+    fn quantize(&mut self, sample: MadFixed32, bits: u32) -> i32 {
+        let shift = 29 - bits;
+        let unity = 0x1000_0000i64;
+        let mut value = i64::from(sample.to_raw()) + (1 << (shift - 1));
+        if self.dither {
+            // The sum of two uniform values spans one LSB either side
+            let lsb = 1i64 << shift;
+            value += self.uniform(lsb) + self.uniform(lsb) - lsb;
+        }
+
+        (value.max(-unity).min(unity - 1) >> shift) as i32
+    }
+
+    /// A pseudo-random value in `0..range`
+    fn uniform(&mut self, range: i64) -> i64 {
+        // xorshift32
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        i64::from(self.seed) % range
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn read_u16(bytes: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([bytes[pos], bytes[pos + 1]])
+    }
+
+    fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    }
+
+    fn to_wav(path: &Path, format: SampleFormat, dither: bool) -> (u64, Vec<u8>) {
+        let mut wav = Cursor::new(Vec::new());
+        let file = File::open(path).unwrap();
+        let samples = if dither {
+            decode_to_wav_dithered(file, &mut wav, format).unwrap()
+        } else {
+            decode_to_wav(file, &mut wav, format).unwrap()
+        };
+        (samples, wav.into_inner())
+    }
+
+    #[test]
+    fn pcm_16_gapless() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let (samples, wav) = to_wav(path, SampleFormat::Int16, false);
+
+        assert_eq!(samples, 193 * 1152 - 576 - 704);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(read_u32(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!(read_u16(&wav, 20), WAVE_FORMAT_PCM);
+        assert_eq!(read_u16(&wav, 22), 2);
+        assert_eq!(read_u32(&wav, 24), 44100);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(read_u32(&wav, 40) as u64, samples * 4);
+
+        // The output starts after the tag frame and the delay
+        let decoded: Vec<i16> = Decoder::decode(File::open(path).unwrap())
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .flat_map(|f| f.samples[0].clone())
+            .skip(1152 + 576 + 529)
+            .take(1000)
+            .map(|s| s.to_i16())
+            .collect();
+        let written: Vec<i16> = (0..1000)
+            .map(|i| read_u16(&wav, 44 + i * 4) as i16)
+            .collect();
+        assert_eq!(written, decoded);
+    }
+
+    #[test]
+    fn extensible_formats() {
+        let path = Path::new("sample_mp3s/constant_single_channel_128.mp3");
+        let expected = 193 * 1152 - 576 - 1456;
+
+        let (samples, wav) = to_wav(path, SampleFormat::Int24, false);
+        assert_eq!(samples, expected);
+        assert_eq!(read_u16(&wav, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(read_u16(&wav, 34), 24);
+        assert_eq!(read_u16(&wav, 44), WAVE_FORMAT_PCM);
+        assert_eq!(read_u32(&wav, 64) as u64, samples * 3);
+        assert_eq!(wav.len() as u64, 68 + samples * 3 + samples % 2);
+
+        let (_, wav) = to_wav(path, SampleFormat::Float32, false);
+        assert_eq!(read_u16(&wav, 44), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(read_u32(&wav, 64) as u64, expected * 4);
+    }
+
+    #[test]
+    fn dither_changes_at_most_one_lsb() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        let (_, plain) = to_wav(path, SampleFormat::Int16, false);
+        let (_, dithered) = to_wav(path, SampleFormat::Int16, true);
+
+        assert_eq!(plain.len(), dithered.len());
+        assert_ne!(plain, dithered);
+        for pos in (44..plain.len()).step_by(2) {
+            let a = i32::from(read_u16(&plain, pos) as i16);
+            let b = i32::from(read_u16(&dithered, pos) as i16);
+            assert!((a - b).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn untagged_stream_is_not_trimmed() {
+        let path = Path::new("sample_mp3s/constant_stereo_16.mp3");
+        let (samples, _) = to_wav(path, SampleFormat::Int16, false);
+        // Includes the last frame, which `Decoder` alone drops at EOF
+        assert_eq!(samples, 211 * 576);
+    }
+
+    #[test]
+    fn raw_matches_wav_data() {
+        let path = Path::new("sample_mp3s/constant_stereo_128.mp3");
+        for &dither in &[false, true] {
+            let (samples, wav) = to_wav(path, SampleFormat::Int16, dither);
+            let mut raw = Vec::new();
+            let file = File::open(path).unwrap();
+            let raw_samples = if dither {
+                decode_to_raw_dithered(file, &mut raw, SampleFormat::Int16).unwrap()
+            } else {
+                decode_to_raw(file, &mut raw, SampleFormat::Int16).unwrap()
+            };
+            assert_eq!(raw_samples, samples);
+            assert_eq!(raw, &wav[44..]);
+        }
+    }
+
+    #[test]
+    fn empty_stream() {
+        let mut wav = Cursor::new(Vec::new());
+        assert!(decode_to_wav(&[][..], &mut wav, SampleFormat::Int16).is_err());
+        assert!(decode_to_raw(&[][..], Vec::new(), SampleFormat::Int16).is_err());
+    }
+}
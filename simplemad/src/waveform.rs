@@ -0,0 +1,254 @@
+//! Waveform overviews for drawing timelines at several zoom levels
+
+use crate::{Decoder, Frame, SimplemadError};
+use std::io::{self, Read, Write};
+
+/// Version of the audiowaveform data format written by `write_dat`
+const DAT_VERSION: i32 = 2;
+
+/// The extremes and loudness of a run of samples of one channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WaveformPoint {
+    /// Lowest sample, as a 16-bit value
+    pub min: i16,
+    /// Highest sample, as a 16-bit value
+    pub max: i16,
+    /// Root mean square of the samples, as a 16-bit value
+    pub rms: i16,
+}
+
+/// A waveform overview at one zoom level
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Waveform {
+    /// Sample rate of the decoded audio
+    pub sample_rate: u32,
+    /// Number of samples per channel summarised by each point
+    pub samples_per_point: u32,
+    /// Number of channels, 1 if the channels were merged
+    pub channels: u16,
+    /// Points for each channel in turn, then the next run of samples
+    pub points: Vec<WaveformPoint>,
+}
+
+impl Waveform {
+    /// Number of points per channel
+    pub fn len(&self) -> usize {
+        self.points.len() / usize::from(self.channels.max(1))
+    }
+
+    /// Whether there are no points
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Write the waveform in the binary format of audiowaveform version 2,
+    /// which holds 16-bit minimum and maximum values but no RMS
+    pub fn write_dat<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(24 + self.points.len() * 4);
+        bytes.extend_from_slice(&DAT_VERSION.to_le_bytes());
+        // Flags, with bit 0 clear for 16-bit values
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.samples_per_point.to_le_bytes());
+        bytes.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&u32::from(self.channels).to_le_bytes());
+
+        for point in &self.points {
+            bytes.extend_from_slice(&point.min.to_le_bytes());
+            bytes.extend_from_slice(&point.max.to_le_bytes());
+        }
+
+        writer.write_all(&bytes)
+    }
+}
+
+/// Running totals for the point being built at one zoom level
+struct Level {
+    waveform: Waveform,
+    count: u32,
+    min: Vec<i16>,
+    max: Vec<i16>,
+    sum_squares: Vec<f64>,
+}
+
+impl Level {
+    fn add(&mut self, channel: usize, sample: i16) {
+        self.min[channel] = self.min[channel].min(sample);
+        self.max[channel] = self.max[channel].max(sample);
+        self.sum_squares[channel] += f64::from(sample) * f64::from(sample);
+    }
+
+    fn end_sample(&mut self) {
+        self.count += 1;
+        if self.count == self.waveform.samples_per_point {
+            self.end_point();
+        }
+    }
+
+    fn end_point(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        for channel in 0..self.min.len() {
+            let rms = (self.sum_squares[channel] / f64::from(self.count)).sqrt();
+            self.waveform.points.push(WaveformPoint {
+                min: self.min[channel],
+                max: self.max[channel],
+                rms: rms.round().min(f64::from(i16::MAX)) as i16,
+            });
+            self.min[channel] = i16::MAX;
+            self.max[channel] = i16::MIN;
+            self.sum_squares[channel] = 0.0;
+        }
+        self.count = 0;
+    }
+}
+
+/// Decode a stream and summarise it at each zoom level, given as a number
+/// of samples per point
+///
+/// With `merge_channels` each point covers every channel, which is what a
+/// single waveform display needs. Decoding with
+/// `Decoder::with_half_sample_rate` roughly halves the time taken, and is
+/// accurate enough for all but the closest zoom levels. The channel count
+/// and sample rate are taken from the first frame. Frames that fail to
+/// decode are skipped.
+pub fn generate<R: Read>(
+    decoder: Decoder<R>,
+    zoom_levels: &[u32],
+    merge_channels: bool,
+) -> Result<Vec<Waveform>, SimplemadError> {
+    let mut levels: Vec<Level> = Vec::new();
+
+    for result in decoder {
+        let frame = match result {
+            Ok(frame) => frame,
+            Err(SimplemadError::Mad(_)) => continue,
+            Err(SimplemadError::EOF) => break,
+            Err(e) => return Err(e),
+        };
+        if frame.samples.is_empty() {
+            continue;
+        }
+        if levels.is_empty() {
+            let channels = if merge_channels {
+                1
+            } else {
+                frame.samples.len()
+            };
+            levels = zoom_levels
+                .iter()
+                .map(|&samples_per_point| Level {
+                    waveform: Waveform {
+                        sample_rate: frame.sample_rate,
+                        samples_per_point: samples_per_point.max(1),
+                        channels: channels as u16,
+                        points: Vec::new(),
+                    },
+                    count: 0,
+                    min: vec![i16::MAX; channels],
+                    max: vec![i16::MIN; channels],
+                    sum_squares: vec![0.0; channels],
+                })
+                .collect();
+        }
+        add_frame(&mut levels, &frame);
+    }
+
+    Ok(levels
+        .into_iter()
+        .map(|mut level| {
+            level.end_point();
+            level.waveform
+        })
+        .collect())
+}
+
+fn add_frame(levels: &mut [Level], frame: &Frame) {
+    let len = frame.samples[0].len();
+    for idx in 0..len {
+        for level in levels.iter_mut() {
+            let channels = level.min.len();
+            for (channel, samples) in frame.samples.iter().enumerate() {
+                // Extra channels, as when a mono stream turns stereo, are
+                // merged into the last one
+                level.add(channel.min(channels - 1), samples[idx].to_i16());
+            }
+            level.end_sample();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::path::Path;
+
+    fn open() -> File {
+        File::open(Path::new("sample_mp3s/constant_stereo_128.mp3")).unwrap()
+    }
+
+    #[test]
+    fn zoom_levels() {
+        let decoder = Decoder::decode(open()).unwrap();
+        let waveforms = generate(decoder, &[256, 1024], false).unwrap();
+        assert_eq!(waveforms.len(), 2);
+
+        // 193 frames of 1152 samples, including the Xing frame
+        let total: usize = 193 * 1152;
+        let (fine, coarse) = (&waveforms[0], &waveforms[1]);
+        assert_eq!(fine.channels, 2);
+        assert_eq!(fine.len(), total.div_ceil(256));
+        assert_eq!(coarse.len(), total.div_ceil(1024));
+
+        // Each coarse point spans four fine ones
+        for (idx, point) in coarse.points.iter().enumerate() {
+            let (channel, run) = (idx % 2, idx / 2);
+            let fine_points = (run * 4..run * 4 + 4)
+                .filter_map(|p| fine.points.get(p * 2 + channel))
+                .collect::<Vec<_>>();
+            assert_eq!(point.min, fine_points.iter().map(|p| p.min).min().unwrap());
+            assert_eq!(point.max, fine_points.iter().map(|p| p.max).max().unwrap());
+            assert!(point.rms <= point.max.max(-point.min));
+        }
+        assert!(fine.points.iter().any(|p| p.rms > 0));
+    }
+
+    #[test]
+    fn half_sample_rate() {
+        let full = generate(Decoder::decode(open()).unwrap(), &[512], true).unwrap();
+        let decoder = Decoder::decode(open()).unwrap().with_half_sample_rate();
+        let half = generate(decoder, &[256], true).unwrap();
+
+        assert_eq!(full[0].channels, 1);
+        assert_eq!(half[0].sample_rate, 22050);
+        assert_eq!(half[0].len(), full[0].len());
+    }
+
+    #[test]
+    fn dat_format() {
+        let waveform = Waveform {
+            sample_rate: 44100,
+            samples_per_point: 512,
+            channels: 2,
+            points: vec![
+                WaveformPoint {
+                    min: -3,
+                    max: 5,
+                    rms: 2
+                };
+                6
+            ],
+        };
+        let mut bytes = Vec::new();
+        waveform.write_dat(&mut bytes).unwrap();
+
+        assert_eq!(bytes.len(), 24 + 6 * 4);
+        assert_eq!(&bytes[..4], &[2, 0, 0, 0]);
+        assert_eq!(&bytes[16..20], &[3, 0, 0, 0]);
+        assert_eq!(&bytes[24..28], &[0xfd, 0xff, 5, 0]);
+    }
+}
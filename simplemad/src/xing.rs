@@ -0,0 +1,168 @@
+//! Parsing of the Xing, Info, LAME and VBRI tags that encoders store in
+//! the first frame of a stream
+
+use simplemad_sys::*;
+
+/// A Xing or Info tag, optionally followed by a LAME extension
+#[derive(Clone, Debug)]
+pub(crate) struct XingHeader {
+    /// `true` for a "Xing" tag, `false` for an "Info" tag written for CBR
+    pub vbr: bool,
+    /// Number of audio frames, not counting the tag frame
+    pub frames: Option<u32>,
+    /// Size of the audio data in bytes, including the tag frame
+    pub bytes: Option<u32>,
+    /// Seek table mapping percentages of the duration to byte positions
+    pub toc: Option<[u8; 100]>,
+    /// LAME extension
+    pub lame: Option<LameTag>,
+}
+
+/// The LAME extension of a Xing or Info tag
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LameTag {
+    /// The raw bytes of the extension, zero past the end of the frame
+    pub bytes: [u8; 36],
+    /// The VBR method nibble, e.g. 1 for CBR and 2 for ABR
+    pub vbr_method: u8,
+    /// Number of samples of silence the encoder added at the start
+    pub encoder_delay: u16,
+    /// Number of samples of silence the encoder added at the end
+    pub padding: u16,
+    /// Peak sample amplitude, where 1.0 is full scale
+    pub peak: Option<f64>,
+    /// Radio, or track, ReplayGain in dB
+    pub track_gain: Option<f64>,
+    /// Audiophile, or album, ReplayGain in dB
+    pub album_gain: Option<f64>,
+}
+
+impl LameTag {
+    pub fn is_abr(self) -> bool {
+        self.vbr_method == 2 || self.vbr_method == 9
+    }
+
+    pub fn is_cbr(self) -> bool {
+        self.vbr_method == 1 || self.vbr_method == 8
+    }
+}
+
+/// A Fraunhofer VBRI tag
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct VbriHeader {
+    /// Size of the audio data in bytes
+    pub bytes: u32,
+    /// Number of audio frames, not counting the tag frame
+    pub frames: u32,
+}
+
+/// Length of the Layer III side information that precedes a Xing tag
+pub(crate) fn side_info_len(header: &MadHeader) -> usize {
+    let mono = header.mode() == Ok(MadMode::SingleChannel);
+    match (header.flags & MAD_FLAG_LSF_EXT != 0, mono) {
+        (false, false) => 32,
+        (false, true) => 17,
+        (true, false) => 17,
+        (true, true) => 9,
+    }
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    let b = bytes.get(pos..pos + 4)?;
+    Some(u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]))
+}
+
+/// Parse a Xing or Info tag from the raw bytes of a frame
+pub(crate) fn parse_xing(frame: &[u8], header: &MadHeader) -> Option<XingHeader> {
+    if header.layer() != Ok(MadLayer::LayerIII) {
+        return None;
+    }
+
+    let mut pos = 4 + side_info_len(header);
+    let vbr = match frame.get(pos..pos + 4)? {
+        b"Xing" => true,
+        b"Info" => false,
+        _ => return None,
+    };
+    let flags = read_u32(frame, pos + 4)?;
+    pos += 8;
+
+    let mut xing = XingHeader {
+        vbr,
+        frames: None,
+        bytes: None,
+        toc: None,
+        lame: None,
+    };
+
+    if flags & 0x1 != 0 {
+        xing.frames = Some(read_u32(frame, pos)?);
+        pos += 4;
+    }
+    if flags & 0x2 != 0 {
+        xing.bytes = Some(read_u32(frame, pos)?);
+        pos += 4;
+    }
+    if flags & 0x4 != 0 {
+        let mut toc = [0; 100];
+        toc.copy_from_slice(frame.get(pos..pos + 100)?);
+        xing.toc = Some(toc);
+        pos += 100;
+    }
+    if flags & 0x8 != 0 {
+        pos += 4;
+    }
+
+    // The LAME extension begins with a nine byte encoder version string
+    if let Some(b"LAME") = frame.get(pos..pos + 4) {
+        if let Some(lame) = frame.get(pos..pos + 24) {
+            let mut bytes = [0; 36];
+            let raw = &frame[pos..frame.len().min(pos + bytes.len())];
+            bytes[..raw.len()].copy_from_slice(raw);
+            // The peak is stored as a fixed-point number with 23 fraction bits
+            let peak = read_u32(lame, 11)?;
+            // Delay and padding are packed into 12 bits each
+            xing.lame = Some(LameTag {
+                bytes,
+                vbr_method: lame[9] & 0x0f,
+                encoder_delay: u16::from(lame[21]) << 4 | u16::from(lame[22]) >> 4,
+                padding: u16::from(lame[22] & 0x0f) << 8 | u16::from(lame[23]),
+                peak: if peak == 0 {
+                    None
+                } else {
+                    Some(f64::from(peak) / f64::from(1 << 23))
+                },
+                track_gain: replay_gain_field(&lame[15..17], 1),
+                album_gain: replay_gain_field(&lame[17..19], 2),
+            });
+        }
+    }
+
+    Some(xing)
+}
+
+/// Decode a LAME ReplayGain field, which packs a three bit name code, a
+/// three bit originator code, a sign bit and nine bits of tenths of a dB
+fn replay_gain_field(field: &[u8], name: u16) -> Option<f64> {
+    let value = u16::from(field[0]) << 8 | u16::from(field[1]);
+    if value >> 13 != name || value >> 10 & 0x7 == 0 {
+        return None;
+    }
+
+    let gain = f64::from(value & 0x1ff) / 10.0;
+    Some(if value & 0x200 != 0 { -gain } else { gain })
+}
+
+/// Parse a VBRI tag from the raw bytes of a frame
+pub(crate) fn parse_vbri(frame: &[u8]) -> Option<VbriHeader> {
+    // The tag always follows 32 bytes of side information
+    let pos = 4 + 32;
+    if frame.get(pos..pos + 4)? != b"VBRI" {
+        return None;
+    }
+
+    Some(VbriHeader {
+        bytes: read_u32(frame, pos + 10)?,
+        frames: read_u32(frame, pos + 14)?,
+    })
+}
@@ -1,4 +1,57 @@
-#[cfg(feature = "vendor")]
+mod layout {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// The words around the values in `src/layout.c`
+    const MAGIC: [u64; 2] = [0x4d41_444c, 0x4159_4f55];
+
+    /// Compile `src/layout.c` with the include paths and defines of
+    /// `build`, and write the sizes and offsets it records to
+    /// `$OUT_DIR/layout.rs`, where `src/lib.rs` checks its own against them
+    pub fn record(mut build: cc::Build) -> Result<(), String> {
+        let objects = build
+            .file("src/layout.c")
+            .warnings(false)
+            .try_compile_intermediates()
+            .map_err(|e| format!("mad.h could not be used: {}", e))?;
+        let object = fs::read(&objects[0]).map_err(|e| e.to_string())?;
+
+        // size_t, which has the width of a pointer on every target libmad
+        // supports
+        let pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
+        let width = pointer_width.parse::<usize>().unwrap() / 8;
+        let big_endian = env::var("CARGO_CFG_TARGET_ENDIAN").unwrap() == "big";
+        let word = |at: usize| -> Option<u64> {
+            let bytes = object.get(at..at + width)?;
+            let byte = |byte: &u8| u64::from(*byte);
+            Some(if big_endian {
+                bytes.iter().fold(0, |acc, b| acc << 8 | byte(b))
+            } else {
+                bytes.iter().rev().fold(0, |acc, b| acc << 8 | byte(b))
+            })
+        };
+        let words_at = |at: usize| (at..).step_by(width).map_while(word);
+
+        let start = (0..object.len())
+            .find(|&at| words_at(at).take(2).eq(MAGIC))
+            .ok_or("the layout of mad.h was not found in the compiled src/layout.c")?;
+        let values: Vec<String> = words_at(start + 2 * width)
+            .take_while(|&value| value != MAGIC[1])
+            .map(|value| value.to_string())
+            .collect();
+
+        let path = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("layout.rs");
+        let source = format!(
+            "/// Sizes and field offsets that `src/layout.c` found in mad.h\n\
+             const MAD_H_LAYOUT: [usize; {}] = [{}];\n",
+            values.len(),
+            values.join(", ")
+        );
+        fs::write(path, source).map_err(|e| e.to_string())
+    }
+}
+
 mod vendor {
     use std::env;
 
@@ -7,32 +60,70 @@ mod vendor {
         build.include("libmad-src");
         build.define("HAVE_CONFIG_H", None);
 
-        let target = env::var("TARGET").unwrap();
-
-        if target.contains("i686") {
-            build
-                .define("FPM_INTEL", None)
-                .define("ASO_ZEROCHECK", None);
-        } else if target.contains("x86_64") {
-            build.define("FPM_64BIT", None);
-        } else if target.contains("arm") {
-            build.define("FPM_ARM", None);
-        // TODO: I'm not sure if compiling assembly works
-        //build.define("ASO_INTERLEAVE1", None);
-        //build.define("ASO_IMDCT", None);
-        //build.file("libmad-src/imdct_l_arm.S");
-        } else if target.contains("mips") {
-            build
-                .define("FPM_MIPS", None)
-                .define("ASO_INTERLEAVE2", None)
-                .define("ASO_ZEROCHECK", None);
-        } else if target.contains("sparc") {
-            build.define("FPM_SPARC", None);
-        } else if target.contains("ppc") || target.contains("powerpc") {
-            build.define("FPM_PPC", None);
-        } else {
-            build.define("FPM_DEFAULT", None);
+        let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+        let pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
+
+        let fpm = match arch.as_str() {
+            "x86" => {
+                build.define("ASO_ZEROCHECK", None);
+                "FPM_INTEL"
+            }
+            "arm" => {
+                // TODO: I'm not sure if compiling assembly works
+                //build.define("ASO_INTERLEAVE1", None);
+                //build.define("ASO_IMDCT", None);
+                //build.file("libmad-src/imdct_l_arm.S");
+                "FPM_ARM"
+            }
+            "mips" => {
+                build
+                    .define("ASO_INTERLEAVE2", None)
+                    .define("ASO_ZEROCHECK", None);
+                "FPM_MIPS"
+            }
+            "sparc" => "FPM_SPARC",
+            "powerpc" => "FPM_PPC",
+            // Any 64-bit target, such as x86_64, aarch64, riscv64,
+            // powerpc64 or s390x, can multiply in 64-bit integers
+            _ if pointer_width == "64" => "FPM_64BIT",
+            _ => "FPM_DEFAULT",
         };
+        build.define(fpm, None);
+
+        let accuracy = env::var_os("CARGO_FEATURE_ACCURACY").is_some();
+        let speed = env::var_os("CARGO_FEATURE_SPEED").is_some();
+        if accuracy && speed {
+            panic!("The `accuracy` and `speed` features of simplemad_sys cannot be combined");
+        }
+        let mut options = Vec::new();
+        if accuracy {
+            options.push("OPT_ACCURACY");
+        }
+        if speed {
+            options.push("OPT_SPEED");
+        }
+        // libmad turns on subband synthesis optimization by itself for
+        // these
+        if env::var_os("CARGO_FEATURE_SSO").is_some() || speed || fpm == "FPM_DEFAULT" {
+            options.push("OPT_SSO");
+        }
+        for option in &options {
+            build.define(option, None);
+        }
+
+        // Compiled with the library's own headers and defines, so that the
+        // layout it reports is the one the library was built with
+        let mut shim = build.clone();
+        shim.define("SIMPLEMAD_VENDOR", None);
+        if let Err(e) = crate::layout::record(shim) {
+            panic!("{}", e);
+        }
+
+        println!("cargo:rustc-env=SIMPLEMAD_SYS_FPM={}", fpm);
+        println!(
+            "cargo:rustc-env=SIMPLEMAD_SYS_OPTIONS={}",
+            options.join(" ")
+        );
 
         build.opt_level(3);
         build.warnings(false);
@@ -51,19 +142,97 @@ mod vendor {
             .file("libmad-src/version.c");
 
         build.compile("libmad.a");
+        println!("cargo:rustc-env=SIMPLEMAD_SYS_LINK=vendored");
     }
 }
 
-#[cfg(not(feature = "vendor"))]
 mod system {
-    pub fn link_libmad() {
-        pkg_config::find_library("mad").expect("Failed to link libmad, is it available?");
+    use std::env;
+    use std::path::PathBuf;
+
+    /// Link the system libmad, from `LIBMAD_LIB_DIR` if set or else found
+    /// through pkg-config
+    pub fn link_libmad(statik: bool) -> Result<(), String> {
+        let include_paths = match env::var_os("LIBMAD_LIB_DIR") {
+            Some(dir) => {
+                let dir = PathBuf::from(dir);
+                if !dir.is_dir() {
+                    return Err(format!(
+                        "LIBMAD_LIB_DIR {} is not a directory",
+                        dir.display()
+                    ));
+                }
+                println!("cargo:rustc-link-search=native={}", dir.display());
+                let kind = if statik { "static" } else { "dylib" };
+                println!("cargo:rustc-link-lib={}=mad", kind);
+                env::var_os("LIBMAD_INCLUDE_DIR")
+                    .map(PathBuf::from)
+                    .into_iter()
+                    .collect()
+            }
+            None => {
+                pkg_config::Config::new()
+                    .statik(statik)
+                    .probe("mad")
+                    .map_err(|e| e.to_string())?
+                    .include_paths
+            }
+        };
+
+        // Compiled against the system mad.h, so that a copy built with
+        // different options or a patched header is caught
+        let mut build = cc::Build::new();
+        for path in &include_paths {
+            build.include(path);
+        }
+        crate::layout::record(build)?;
+
+        println!("cargo:rustc-env=SIMPLEMAD_SYS_FPM=");
+        println!("cargo:rustc-env=SIMPLEMAD_SYS_OPTIONS=");
+        let link = if statik { "system-static" } else { "system" };
+        println!("cargo:rustc-env=SIMPLEMAD_SYS_LINK={}", link);
+        Ok(())
     }
 }
 
+/// Whether an environment variable is set to anything but "0"
+fn env_flag(name: &str) -> bool {
+    println!("cargo:rerun-if-env-changed={}", name);
+    std::env::var_os(name).is_some_and(|value| value != "0")
+}
+
 fn main() {
-    #[cfg(feature = "vendor")]
-    vendor::vendor_libmad();
-    #[cfg(not(feature = "vendor"))]
-    system::link_libmad();
+    // Printing any rerun-if line turns off rerunning on every change in
+    // the package, so the sources have to be listed too
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/layout.c");
+    println!("cargo:rerun-if-changed=libmad-src");
+    let feature = |name: &str| std::env::var_os(format!("CARGO_FEATURE_{}", name)).is_some();
+    let no_vendor = env_flag("LIBMAD_NO_VENDOR");
+    let statik = feature("STATIC") || env_flag("LIBMAD_STATIC");
+    println!("cargo:rerun-if-env-changed=LIBMAD_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=LIBMAD_INCLUDE_DIR");
+
+    if feature("VENDOR") && !no_vendor {
+        vendor::vendor_libmad();
+        return;
+    }
+
+    match system::link_libmad(statik) {
+        Ok(()) => {}
+        Err(e) if feature("VENDOR_FALLBACK") && !no_vendor => {
+            println!(
+                "cargo:warning=simplemad_sys: system libmad not usable ({}), \
+                 compiling the bundled copy",
+                e.trim().lines().next().unwrap_or_default()
+            );
+            vendor::vendor_libmad();
+        }
+        Err(e) => panic!(
+            "Failed to link libmad: {}\n\
+             Install libmad and its pkg-config file, point LIBMAD_LIB_DIR at it, \
+             or enable the `vendor` or `vendor-fallback` feature of simplemad_sys",
+            e.trim()
+        ),
+    }
 }
@@ -0,0 +1,100 @@
+/*
+ * Sizes and field offsets of the libmad structures that simplemad_sys
+ * declares by hand, as seen by the C compiler. This file is only compiled,
+ * never linked: build.rs finds the values between the markers in the object
+ * file, and the Rust side compares them with its own definitions, in the
+ * same order, when it is compiled.
+ */
+
+# include <stddef.h>
+
+# ifdef SIMPLEMAD_VENDOR
+#  include "config.h"
+#  include "global.h"
+#  include "stream.h"
+#  include "frame.h"
+#  include "synth.h"
+#  include "decoder.h"
+# else
+#  include <mad.h>
+# endif
+
+# define SIZE(type)		sizeof(type)
+# define FIELD(type, field)	offsetof(type, field)
+
+# define MAGIC1			0x4d41444c  /* "MADL" */
+# define MAGIC2			0x41594f55  /* "AYOU" */
+
+size_t const simplemad_sys_layout[] = {
+  MAGIC1, MAGIC2,
+
+  SIZE(struct mad_bitptr),
+  FIELD(struct mad_bitptr, byte),
+  FIELD(struct mad_bitptr, cache),
+  FIELD(struct mad_bitptr, left),
+
+  SIZE(struct mad_stream),
+  FIELD(struct mad_stream, buffer),
+  FIELD(struct mad_stream, bufend),
+  FIELD(struct mad_stream, skiplen),
+  FIELD(struct mad_stream, sync),
+  FIELD(struct mad_stream, freerate),
+  FIELD(struct mad_stream, this_frame),
+  FIELD(struct mad_stream, next_frame),
+  FIELD(struct mad_stream, ptr),
+  FIELD(struct mad_stream, anc_ptr),
+  FIELD(struct mad_stream, anc_bitlen),
+  FIELD(struct mad_stream, main_data),
+  FIELD(struct mad_stream, md_len),
+  FIELD(struct mad_stream, options),
+  FIELD(struct mad_stream, error),
+
+  SIZE(mad_timer_t),
+  FIELD(mad_timer_t, seconds),
+  FIELD(mad_timer_t, fraction),
+
+  SIZE(struct mad_header),
+  FIELD(struct mad_header, layer),
+  FIELD(struct mad_header, mode),
+  FIELD(struct mad_header, mode_extension),
+  FIELD(struct mad_header, emphasis),
+  FIELD(struct mad_header, bitrate),
+  FIELD(struct mad_header, samplerate),
+  FIELD(struct mad_header, crc_check),
+  FIELD(struct mad_header, crc_target),
+  FIELD(struct mad_header, flags),
+  FIELD(struct mad_header, private_bits),
+  FIELD(struct mad_header, duration),
+
+  SIZE(struct mad_frame),
+  FIELD(struct mad_frame, header),
+  FIELD(struct mad_frame, options),
+  FIELD(struct mad_frame, sbsample),
+  FIELD(struct mad_frame, overlap),
+
+  SIZE(struct mad_pcm),
+  FIELD(struct mad_pcm, samplerate),
+  FIELD(struct mad_pcm, channels),
+  FIELD(struct mad_pcm, length),
+  FIELD(struct mad_pcm, samples),
+
+  SIZE(struct mad_synth),
+  FIELD(struct mad_synth, filter),
+  FIELD(struct mad_synth, phase),
+  FIELD(struct mad_synth, pcm),
+
+  SIZE(struct mad_decoder),
+  FIELD(struct mad_decoder, mode),
+  FIELD(struct mad_decoder, options),
+  FIELD(struct mad_decoder, async),
+  FIELD(struct mad_decoder, sync),
+  FIELD(struct mad_decoder, cb_data),
+  FIELD(struct mad_decoder, input_func),
+  FIELD(struct mad_decoder, header_func),
+  FIELD(struct mad_decoder, filter_func),
+  FIELD(struct mad_decoder, output_func),
+  FIELD(struct mad_decoder, error_func),
+  FIELD(struct mad_decoder, message_func),
+
+  MAGIC2, MAGIC1
+};
@@ -1,7 +1,9 @@
 #![crate_name = "simplemad_sys"]
 
 use libc::{c_char, c_int, c_long, c_uint, c_ushort, c_void};
+use std::convert::TryFrom;
 use std::fmt::{self};
+use std::mem::{offset_of, size_of};
 use std::ptr;
 
 pub use libc::c_ulong;
@@ -9,22 +11,18 @@ pub use libc::c_ulong;
 extern "C" {
     pub fn mad_decoder_init(
         decoder: *mut MadDecoder,
-        message: *mut c_void,
-        input_cb: extern "C" fn(message: *mut c_void, stream: &MadStream) -> MadFlow,
-        header_cb: extern "C" fn(message: *mut c_void, header: &MadHeader) -> MadFlow,
-        filter_cb: extern "C" fn(),
-        output_cb: extern "C" fn(message: *mut c_void, header: &MadHeader, pcm: &MadPcm) -> MadFlow,
-        error_cb: extern "C" fn(
-            message: *mut c_void,
-            stream: &MadStream,
-            frame: *const c_void,
-        ) -> MadFlow,
-        message_cb: extern "C" fn(),
+        data: *mut c_void,
+        input_func: Option<MadInputFunc>,
+        header_func: Option<MadHeaderFunc>,
+        filter_func: Option<MadFilterFunc>,
+        output_func: Option<MadOutputFunc>,
+        error_func: Option<MadErrorFunc>,
+        message_func: Option<MadMessageFunc>,
     );
 
     pub fn mad_decoder_run(decoder: &mut MadDecoder, mode: MadDecoderMode) -> c_int;
     pub fn mad_decoder_finish(decoder: &mut MadDecoder) -> c_int;
-    pub fn mad_stream_buffer(stream: &MadStream, buf_start: *const u8, buf_len: c_ulong);
+    pub fn mad_stream_buffer(stream: &mut MadStream, buf_start: *const u8, buf_len: c_ulong);
 
     pub fn mad_header_init(header: &mut MadHeader);
     pub fn mad_stream_init(stream: &mut MadStream);
@@ -34,13 +32,51 @@ extern "C" {
     pub fn mad_stream_finish(stream: &mut MadStream);
     pub fn mad_frame_finish(frame: &mut MadFrame);
 
+    pub fn mad_frame_mute(frame: &mut MadFrame);
+    pub fn mad_synth_mute(synth: &mut MadSynth);
+
     pub fn mad_header_decode(header: &mut MadHeader, stream: &mut MadStream);
     pub fn mad_frame_decode(frame: &mut MadFrame, stream: &mut MadStream);
-    pub fn mad_synth_frame(synth: &mut MadSynth, frame: &mut MadFrame);
+    pub fn mad_synth_frame(synth: &mut MadSynth, frame: &MadFrame);
+
+    // NUL-terminated strings, declared empty as their length is unknown
+
+    /// "MPEG Audio Decoder" and the version number
+    pub static mad_version: [c_char; 0];
+    /// The copyright notice
+    pub static mad_copyright: [c_char; 0];
+    /// The author's name and email address
+    pub static mad_author: [c_char; 0];
+    /// The options libmad was compiled with, separated by spaces
+    pub static mad_build: [c_char; 0];
 }
 
+/// Supplies more input with `mad_stream_buffer`, for `mad_decoder_run`
+pub type MadInputFunc = unsafe extern "C" fn(data: *mut c_void, stream: *mut MadStream) -> MadFlow;
+/// Called with each frame header before the frame is decoded
+pub type MadHeaderFunc =
+    unsafe extern "C" fn(data: *mut c_void, header: *const MadHeader) -> MadFlow;
+/// Called with each decoded frame before synthesis
+pub type MadFilterFunc = unsafe extern "C" fn(
+    data: *mut c_void,
+    stream: *const MadStream,
+    frame: *mut MadFrame,
+) -> MadFlow;
+/// Called with the PCM synthesized from each frame
+pub type MadOutputFunc =
+    unsafe extern "C" fn(data: *mut c_void, header: *const MadHeader, pcm: *mut MadPcm) -> MadFlow;
+/// Called with each recoverable error, left in `MadStream::error`
+pub type MadErrorFunc = unsafe extern "C" fn(
+    data: *mut c_void,
+    stream: *mut MadStream,
+    frame: *mut MadFrame,
+) -> MadFlow;
+/// Handles messages in asynchronous mode, which the vendored build lacks
+pub type MadMessageFunc =
+    unsafe extern "C" fn(data: *mut c_void, message: *mut c_void, len: *mut c_uint) -> MadFlow;
+
 /// libmad callbacks return `MadFlow` values, which are used to control the decoding process
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub enum MadFlow {
     /// continue normally
@@ -57,8 +93,10 @@ pub enum MadFlow {
 }
 
 /// Errors generated by libmad
+///
+/// libmad stores these in `MadStream::error` as plain integers; convert
+/// them with `TryFrom<c_int>`.
 #[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(C)]
 pub enum MadError {
     /// no error
     None = 0x0000,
@@ -133,6 +171,39 @@ impl Default for MadError {
     }
 }
 
+impl TryFrom<c_int> for MadError {
+    type Error = c_int;
+
+    /// Convert an error code, returning it unchanged if it is unknown
+    fn try_from(value: c_int) -> Result<MadError, c_int> {
+        Ok(match value {
+            0x0000 => MadError::None,
+            0x0001 => MadError::BufLen,
+            0x0002 => MadError::BufPtr,
+            0x0031 => MadError::NoMem,
+            0x0101 => MadError::LostSync,
+            0x0102 => MadError::BadLayer,
+            0x0103 => MadError::BadBitRate,
+            0x0104 => MadError::BadSampleRate,
+            0x0105 => MadError::BadEmphasis,
+            0x0201 => MadError::BadCRC,
+            0x0211 => MadError::BadBitAlloc,
+            0x0221 => MadError::BadScaleFactor,
+            0x0222 => MadError::BadMode,
+            0x0231 => MadError::BadFrameLen,
+            0x0232 => MadError::BadBigValues,
+            0x0233 => MadError::BadBlockType,
+            0x0234 => MadError::BadScFSI,
+            0x0235 => MadError::BadDataPtr,
+            0x0236 => MadError::BadPart3Len,
+            0x0237 => MadError::BadHuffTable,
+            0x0238 => MadError::BadHuffData,
+            0x0239 => MadError::BadStereo,
+            _ => return Err(value),
+        })
+    }
+}
+
 #[repr(C)]
 pub struct MadBitPtr {
     pub byte: *mut c_char,
@@ -165,7 +236,15 @@ pub struct MadStream {
     pub buffer_mdlen: *mut c_char,
     pub md_len: c_uint,
     pub options: c_int,
-    pub error: MadError,
+    /// A `MadError` code
+    pub error: c_int,
+}
+
+impl MadStream {
+    /// The error of the last operation on the stream, or the unknown code
+    pub fn error(&self) -> Result<MadError, c_int> {
+        MadError::try_from(self.error)
+    }
 }
 
 impl Default for MadStream {
@@ -184,7 +263,7 @@ impl Default for MadStream {
             buffer_mdlen: ptr::null::<c_char>() as *mut c_char,
             md_len: 0,
             options: 0,
-            error: MadError::None,
+            error: 0,
         }
     }
 }
@@ -236,8 +315,8 @@ impl fmt::Debug for MadSynth {
     }
 }
 
+/// MPEG audio layer, stored in `MadHeader::layer` as a plain integer
 #[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(C)]
 pub enum MadLayer {
     LayerI = 1,
     LayerII = 2,
@@ -250,8 +329,21 @@ impl Default for MadLayer {
     }
 }
 
+impl TryFrom<c_int> for MadLayer {
+    type Error = c_int;
+
+    fn try_from(value: c_int) -> Result<MadLayer, c_int> {
+        match value {
+            1 => Ok(MadLayer::LayerI),
+            2 => Ok(MadLayer::LayerII),
+            3 => Ok(MadLayer::LayerIII),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Channel mode, stored in `MadHeader::mode` as a plain integer
 #[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(C)]
 pub enum MadMode {
     SingleChannel = 0,
     DualChannel = 1,
@@ -265,8 +357,23 @@ impl Default for MadMode {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
+impl TryFrom<c_int> for MadMode {
+    type Error = c_int;
+
+    fn try_from(value: c_int) -> Result<MadMode, c_int> {
+        match value {
+            0 => Ok(MadMode::SingleChannel),
+            1 => Ok(MadMode::DualChannel),
+            2 => Ok(MadMode::JointStereo),
+            3 => Ok(MadMode::Stereo),
+            _ => Err(value),
+        }
+    }
+}
+
+/// De-emphasis to apply, stored in `MadHeader::emphasis` as a plain
+/// integer
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MadEmphasis {
     None = 0,
     Fifty15Us = 1,
@@ -280,6 +387,20 @@ impl Default for MadEmphasis {
     }
 }
 
+impl TryFrom<c_int> for MadEmphasis {
+    type Error = c_int;
+
+    fn try_from(value: c_int) -> Result<MadEmphasis, c_int> {
+        match value {
+            0 => Ok(MadEmphasis::None),
+            1 => Ok(MadEmphasis::Fifty15Us),
+            2 => Ok(MadEmphasis::Reserved),
+            3 => Ok(MadEmphasis::CcittJ17),
+            _ => Err(value),
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 #[repr(C)]
 pub struct MadTimer {
@@ -287,13 +408,49 @@ pub struct MadTimer {
     pub fraction: c_ulong,
 }
 
+/// number of Layer III private bits
+pub const MAD_FLAG_NPRIVATE_III: c_int = 0x0007;
+/// header but not data is decoded
+pub const MAD_FLAG_INCOMPLETE: c_int = 0x0008;
+/// frame has CRC protection
+pub const MAD_FLAG_PROTECTION: c_int = 0x0010;
+/// frame is copyright
+pub const MAD_FLAG_COPYRIGHT: c_int = 0x0020;
+/// frame is original (else copy)
+pub const MAD_FLAG_ORIGINAL: c_int = 0x0040;
+/// frame has additional slot
+pub const MAD_FLAG_PADDING: c_int = 0x0080;
+/// uses intensity joint stereo
+pub const MAD_FLAG_I_STEREO: c_int = 0x0100;
+/// uses middle/side joint stereo
+pub const MAD_FLAG_MS_STEREO: c_int = 0x0200;
+/// uses free format bitrate
+pub const MAD_FLAG_FREEFORMAT: c_int = 0x0400;
+/// lower sampling freq. extension
+pub const MAD_FLAG_LSF_EXT: c_int = 0x1000;
+/// multichannel audio extension
+pub const MAD_FLAG_MC_EXT: c_int = 0x2000;
+/// MPEG 2.5 (unofficial) extension
+pub const MAD_FLAG_MPEG_2_5_EXT: c_int = 0x4000;
+
+/// ignore CRC errors
+pub const MAD_OPTION_IGNORECRC: c_int = 0x0001;
+/// generate PCM at 1/2 sample rate
+pub const MAD_OPTION_HALFSAMPLERATE: c_int = 0x0002;
+
+/// Resolution of the `fraction` field of `MadTimer`
+pub const MAD_TIMER_RESOLUTION: c_ulong = 352_800_000;
+
 #[derive(Default, Debug, Clone, Copy)]
 #[repr(C)]
 pub struct MadHeader {
-    pub layer: MadLayer,
-    pub mode: MadMode,
+    /// A `MadLayer` value
+    pub layer: c_int,
+    /// A `MadMode` value
+    pub mode: c_int,
     pub mode_extension: c_int,
-    pub emphasis: MadEmphasis,
+    /// A `MadEmphasis` value
+    pub emphasis: c_int,
     pub bit_rate: c_ulong,
     pub sample_rate: c_uint,
     pub crc_check: c_ushort,
@@ -303,6 +460,23 @@ pub struct MadHeader {
     pub duration: MadTimer,
 }
 
+impl MadHeader {
+    /// The layer, or the unknown value
+    pub fn layer(&self) -> Result<MadLayer, c_int> {
+        MadLayer::try_from(self.layer)
+    }
+
+    /// The channel mode, or the unknown value
+    pub fn mode(&self) -> Result<MadMode, c_int> {
+        MadMode::try_from(self.mode)
+    }
+
+    /// The emphasis, or the unknown value
+    pub fn emphasis(&self) -> Result<MadEmphasis, c_int> {
+        MadEmphasis::try_from(self.emphasis)
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct MadPcm {
@@ -354,38 +528,227 @@ struct MadAsyncParameters {
     aout: c_int,
 }
 
+/// State for `mad_decoder_run`, set up by `mad_decoder_init`
 #[repr(C)]
 pub struct MadDecoder {
-    mode: MadDecoderMode,
-    options: c_int,
+    /// A `MadDecoderMode` value, or -1 when not running
+    pub mode: c_int,
+    /// `MAD_OPTION_*` flags for the stream
+    pub options: c_int,
     r#async: MadAsyncParameters,
-    sync: *const c_void,
-    cb_data: *const c_void,
-    input_func: *const c_void,
-    header_func: *const c_void,
-    filter_func: *const c_void,
-    output_func: *const c_void,
-    error_func: *const c_void,
-    message_func: *const c_void,
+    sync: *mut c_void,
+    pub cb_data: *mut c_void,
+    pub input_func: Option<MadInputFunc>,
+    pub header_func: Option<MadHeaderFunc>,
+    pub filter_func: Option<MadFilterFunc>,
+    pub output_func: Option<MadOutputFunc>,
+    pub error_func: Option<MadErrorFunc>,
+    pub message_func: Option<MadMessageFunc>,
 }
 
 impl Default for MadDecoder {
     fn default() -> MadDecoder {
         MadDecoder {
-            mode: Default::default(),
+            mode: -1,
             options: 0,
             r#async: Default::default(),
-            sync: ptr::null::<c_void>(),
-            cb_data: ptr::null::<c_void>(),
-            input_func: ptr::null::<c_void>(),
-            header_func: ptr::null::<c_void>(),
-            filter_func: ptr::null::<c_void>(),
-            output_func: ptr::null::<c_void>(),
-            error_func: ptr::null::<c_void>(),
-            message_func: ptr::null::<c_void>(),
+            sync: ptr::null_mut(),
+            cb_data: ptr::null_mut(),
+            input_func: None,
+            header_func: None,
+            filter_func: None,
+            output_func: None,
+            error_func: None,
+            message_func: None,
         }
     }
 }
 
+/// How the linked libmad was configured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BuildConfig {
+    /// Whether libmad was compiled from the bundled sources
+    pub vendored: bool,
+    /// Whether libmad was linked statically, as it always is if vendored
+    pub statik: bool,
+    /// The fixed-point implementation, such as `FPM_64BIT`, if vendored
+    pub fpm: Option<&'static str>,
+    /// Whether `OPT_ACCURACY` was set, favouring precision over speed
+    pub accuracy: bool,
+    /// Whether `OPT_SPEED` was set, favouring speed over precision
+    pub speed: bool,
+    /// Whether `OPT_SSO` was set, for faster but less accurate synthesis
+    pub sso: bool,
+}
+
+/// How the linked libmad was configured by the build script
+///
+/// The options of a system libmad are unknown and reported as unset.
+/// libmad is vendored with the `vendor` feature, or with `vendor-fallback`
+/// if pkg-config does not find it, unless `LIBMAD_NO_VENDOR` was set.
+pub fn build_config() -> BuildConfig {
+    let link = env!("SIMPLEMAD_SYS_LINK");
+    let fpm = env!("SIMPLEMAD_SYS_FPM");
+    let options: Vec<&str> = env!("SIMPLEMAD_SYS_OPTIONS").split_whitespace().collect();
+    BuildConfig {
+        vendored: link == "vendored",
+        statik: link != "system",
+        fpm: if fpm.is_empty() { None } else { Some(fpm) },
+        accuracy: options.contains(&"OPT_ACCURACY"),
+        speed: options.contains(&"OPT_SPEED"),
+        sso: options.contains(&"OPT_SSO"),
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+
+/// Sizes and field offsets of the structures above, in the order that
+/// `src/layout.c` records them for the libmad being linked
+const LAYOUT: &[(&str, usize)] = &[
+    ("mad_bitptr", size_of::<MadBitPtr>()),
+    ("mad_bitptr.byte", offset_of!(MadBitPtr, byte)),
+    ("mad_bitptr.cache", offset_of!(MadBitPtr, cache)),
+    ("mad_bitptr.left", offset_of!(MadBitPtr, left)),
+    ("mad_stream", size_of::<MadStream>()),
+    ("mad_stream.buffer", offset_of!(MadStream, buffer)),
+    ("mad_stream.bufend", offset_of!(MadStream, buff_end)),
+    ("mad_stream.skiplen", offset_of!(MadStream, skip_len)),
+    ("mad_stream.sync", offset_of!(MadStream, sync)),
+    ("mad_stream.freerate", offset_of!(MadStream, free_rate)),
+    ("mad_stream.this_frame", offset_of!(MadStream, this_frame)),
+    ("mad_stream.next_frame", offset_of!(MadStream, next_frame)),
+    ("mad_stream.ptr", offset_of!(MadStream, ptr)),
+    ("mad_stream.anc_ptr", offset_of!(MadStream, anc_ptr)),
+    ("mad_stream.anc_bitlen", offset_of!(MadStream, anc_bitlen)),
+    ("mad_stream.main_data", offset_of!(MadStream, buffer_mdlen)),
+    ("mad_stream.md_len", offset_of!(MadStream, md_len)),
+    ("mad_stream.options", offset_of!(MadStream, options)),
+    ("mad_stream.error", offset_of!(MadStream, error)),
+    ("mad_timer_t", size_of::<MadTimer>()),
+    ("mad_timer_t.seconds", offset_of!(MadTimer, seconds)),
+    ("mad_timer_t.fraction", offset_of!(MadTimer, fraction)),
+    ("mad_header", size_of::<MadHeader>()),
+    ("mad_header.layer", offset_of!(MadHeader, layer)),
+    ("mad_header.mode", offset_of!(MadHeader, mode)),
+    (
+        "mad_header.mode_extension",
+        offset_of!(MadHeader, mode_extension),
+    ),
+    ("mad_header.emphasis", offset_of!(MadHeader, emphasis)),
+    ("mad_header.bitrate", offset_of!(MadHeader, bit_rate)),
+    ("mad_header.samplerate", offset_of!(MadHeader, sample_rate)),
+    ("mad_header.crc_check", offset_of!(MadHeader, crc_check)),
+    ("mad_header.crc_target", offset_of!(MadHeader, crc_target)),
+    ("mad_header.flags", offset_of!(MadHeader, flags)),
+    (
+        "mad_header.private_bits",
+        offset_of!(MadHeader, private_bits),
+    ),
+    ("mad_header.duration", offset_of!(MadHeader, duration)),
+    ("mad_frame", size_of::<MadFrame>()),
+    ("mad_frame.header", offset_of!(MadFrame, header)),
+    ("mad_frame.options", offset_of!(MadFrame, options)),
+    ("mad_frame.sbsample", offset_of!(MadFrame, sbsample)),
+    ("mad_frame.overlap", offset_of!(MadFrame, overlap)),
+    ("mad_pcm", size_of::<MadPcm>()),
+    ("mad_pcm.samplerate", offset_of!(MadPcm, sample_rate)),
+    ("mad_pcm.channels", offset_of!(MadPcm, channels)),
+    ("mad_pcm.length", offset_of!(MadPcm, length)),
+    ("mad_pcm.samples", offset_of!(MadPcm, samples)),
+    ("mad_synth", size_of::<MadSynth>()),
+    ("mad_synth.filter", offset_of!(MadSynth, filter)),
+    ("mad_synth.phase", offset_of!(MadSynth, phase)),
+    ("mad_synth.pcm", offset_of!(MadSynth, pcm)),
+    ("mad_decoder", size_of::<MadDecoder>()),
+    ("mad_decoder.mode", offset_of!(MadDecoder, mode)),
+    ("mad_decoder.options", offset_of!(MadDecoder, options)),
+    ("mad_decoder.async", offset_of!(MadDecoder, r#async)),
+    ("mad_decoder.sync", offset_of!(MadDecoder, sync)),
+    ("mad_decoder.cb_data", offset_of!(MadDecoder, cb_data)),
+    ("mad_decoder.input_func", offset_of!(MadDecoder, input_func)),
+    (
+        "mad_decoder.header_func",
+        offset_of!(MadDecoder, header_func),
+    ),
+    (
+        "mad_decoder.filter_func",
+        offset_of!(MadDecoder, filter_func),
+    ),
+    (
+        "mad_decoder.output_func",
+        offset_of!(MadDecoder, output_func),
+    ),
+    ("mad_decoder.error_func", offset_of!(MadDecoder, error_func)),
+    (
+        "mad_decoder.message_func",
+        offset_of!(MadDecoder, message_func),
+    ),
+];
+
+// Fail the build if a structure above differs from the mad.h that libmad
+// was compiled with. The error names the size or offset that differs.
+const _: () = {
+    assert!(
+        LAYOUT.len() == MAD_H_LAYOUT.len(),
+        "src/layout.c and LAYOUT list different fields"
+    );
+    let mut i = 0;
+    while i < LAYOUT.len() {
+        if LAYOUT[i].1 != MAD_H_LAYOUT[i] {
+            // Names the structure or field that differs from mad.h
+            panic!("{}", LAYOUT[i].0);
+        }
+        i += 1;
+    }
+};
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+
+    #[test]
+    fn unknown_values() {
+        assert_eq!(MadError::try_from(0x0235), Ok(MadError::BadDataPtr));
+        assert_eq!(MadError::try_from(0x0300), Err(0x0300));
+        assert_eq!(MadLayer::try_from(3), Ok(MadLayer::LayerIII));
+        assert_eq!(MadLayer::try_from(0), Err(0));
+        assert_eq!(MadMode::try_from(7), Err(7));
+        assert_eq!(MadEmphasis::try_from(2), Ok(MadEmphasis::Reserved));
+
+        // Values that libmad could write are read back without undefined
+        // behaviour, whatever they are
+        let header = MadHeader {
+            layer: 4,
+            emphasis: 3,
+            ..Default::default()
+        };
+        assert_eq!(header.layer(), Err(4));
+        assert_eq!(header.emphasis(), Ok(MadEmphasis::CcittJ17));
+    }
+
+    #[test]
+    fn reports_build_config() {
+        let config = build_config();
+        assert_eq!(config.vendored, config.fpm.is_some());
+        assert!(!(config.accuracy && config.speed));
+        if config.speed {
+            assert!(config.sso);
+        }
+        if config.vendored && cfg!(target_pointer_width = "64") {
+            assert_eq!(config.fpm, Some("FPM_64BIT"));
+        }
+    }
+
+    #[test]
+    fn version_strings() {
+        let string = |s: &[c_char; 0]| unsafe { std::ffi::CStr::from_ptr(s.as_ptr()) };
+        unsafe {
+            assert!(string(&mad_version).to_bytes().ends_with(b"0.15.1 (beta)"));
+            assert!(string(&mad_author).to_bytes().contains(&b'@'));
+            if let Some(fpm) = build_config().fpm {
+                let build = string(&mad_build).to_str().unwrap();
+                assert!(build.split_whitespace().any(|option| option == fpm));
+            }
+        }
+    }
+}